@@ -0,0 +1,323 @@
+// assembles CT64k programs while rustc compiles the crate using them:
+//
+//   mod hello {
+//     ct64k_asm! {
+//       main:
+//         mi STDOUT, 0x41
+//         hf
+//     }
+//   }
+//
+// defines `hello::PROGRAM', a `[u16; N]' loaded at 0x1000, and
+// `hello::symbols', with a `u16' constant for every label.
+// `ct64k_include!("file.asm")' does the same for a file, found relative to
+// the rust source, like include_str!. rust strips `//' comments before the
+// assembler sees them, so use those inside ct64k_asm!; a `;' comment still
+// has to lex as rust tokens.
+
+// `field: field` is the house style; it predates shorthand initialization
+#![allow(clippy::redundant_field_names)]
+
+extern crate assembler;
+extern crate proc_macro;
+
+use std::path::{Path, PathBuf};
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span};
+use proc_macro::{TokenStream, TokenTree};
+
+use assembler::export::Language;
+use assembler::macros::{catch_errors, Diagnostic};
+use assembler::parser::Options;
+use assembler::Program;
+
+// what positions in a ct64k_asm! refer to; it's next to the rust source,
+// so that imports are found there
+const INLINE_NAME: &str = "<ct64k_asm!>";
+
+/// ```
+/// #[macro_use]
+/// extern crate ct64k_macros;
+///
+/// ct64k_asm! {
+///   main:
+///     ji main
+/// }
+///
+/// fn main() {
+///   assert_eq!(symbols::main, 0x1000);
+/// }
+/// ```
+///
+/// errors are compile errors, at the line they're on:
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate ct64k_macros;
+///
+/// ct64k_asm! {
+///   main:
+///     ji nowhere
+/// }
+///
+/// fn main() {}
+/// ```
+#[proc_macro]
+pub fn ct64k_asm(input: TokenStream) -> TokenStream {
+  let mut source = Source {
+    text: String::new(),
+    first_line: 0,
+    line: 0,
+    column: 0,
+    lines: Vec::new(),
+  };
+  source.push_stream(input);
+  let path = base_dir().join(INLINE_NAME);
+  let (assembled, reported) = catch_errors(|| {
+    Program::from_source(&path, &source.text, &Options::default())
+  });
+  // the diagnostic's line, counted from the first line of the macro
+  let span_of = |diagnostic: &Diagnostic| match diagnostic.position {
+    Some((ref file, line)) if Path::new(file) == path => {
+      source.lines.get(line.wrapping_sub(1)).and_then(|&span| span)
+    },
+    _ => None,
+  };
+  for diagnostic in &reported {
+    print(diagnostic, span_of(diagnostic));
+  }
+  match assembled {
+    Ok(program) => expand(&program),
+    Err(error) => match span_of(&error) {
+      Some(span) => compile_error(&error.message, span),
+      None => compile_error(&describe(&error), Span::call_site()),
+    },
+  }
+}
+
+#[proc_macro]
+pub fn ct64k_include(input: TokenStream) -> TokenStream {
+  let mut tokens = input.into_iter();
+  let (literal, span) = match (tokens.next(), tokens.next()) {
+    (Some(TokenTree::Literal(literal)), None) => {
+      let span = literal.span();
+      (literal, span)
+    },
+    _ => {
+      return compile_error(
+        "ct64k_include! takes one string literal", Span::call_site(),
+      );
+    },
+  };
+  let filename = match string_value(&literal.to_string()) {
+    Some(filename) => filename,
+    None => {
+      let message = format!("Expected a file name, found {}", literal);
+      return compile_error(&message, span);
+    },
+  };
+  let path = base_dir().join(filename);
+  let (assembled, reported) = catch_errors(|| {
+    Program::new(path.to_str().unwrap(), &Options::default())
+  });
+  for diagnostic in &reported {
+    print(diagnostic, None);
+  }
+  match assembled {
+    Ok(program) => expand(&program),
+    Err(error) => compile_error(&describe(&error), span),
+  }
+}
+
+// the assembly in a ct64k_asm!, rebuilt from the tokens and where they
+// were, since the assembler cares about newlines
+struct Source {
+  text: String,
+  // where the macro starts, in the rust source
+  first_line: usize,
+  line: usize,
+  column: usize,
+  // the span of the first token on each line
+  lines: Vec<Option<Span>>,
+}
+
+impl Source {
+  fn push_stream(&mut self, stream: TokenStream) {
+    for token in stream {
+      match token {
+        TokenTree::Group(group) => {
+          let (open, close) = match group.delimiter() {
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::Bracket => ("[", "]"),
+            Delimiter::None => {
+              self.push_stream(group.stream());
+              continue;
+            },
+          };
+          self.push(group.span_open(), open);
+          self.push_stream(group.stream());
+          self.push(group.span_close(), close);
+        },
+        token => {
+          let span = token.span();
+          let text = span.source_text().unwrap_or_else(|| token.to_string());
+          self.push(span, &text);
+        },
+      }
+    }
+  }
+
+  fn push(&mut self, span: Span, text: &str) {
+    let (line, column) = (span.line(), span.column());
+    if self.text.is_empty() {
+      self.first_line = line;
+      self.line = line;
+      self.column = column;
+    }
+    if line > self.line {
+      for _ in self.line..line {
+        self.text.push('\n');
+      }
+      self.line = line;
+      self.column = 1;
+    }
+    // tokens which came from somewhere else, like a macro_rules!, can be
+    // out of order; they still need to stay apart
+    if column > self.column {
+      for _ in self.column..column {
+        self.text.push(' ');
+      }
+    } else if column < self.column && !self.text.ends_with(' ') {
+      self.text.push(' ');
+    }
+    let idx = line.saturating_sub(self.first_line);
+    if self.lines.len() <= idx {
+      self.lines.resize(idx + 1, None);
+    }
+    if self.lines[idx].is_none() {
+      self.lines[idx] = Some(span);
+    }
+
+    self.text.push_str(text);
+    let end = span.end();
+    self.line = self.line.max(end.line());
+    self.column = end.column();
+  }
+}
+
+// the directory of the rust source which invoked the macro
+fn base_dir() -> PathBuf {
+  match Span::call_site().local_file() {
+    Some(file) => match file.parent() {
+      Some(dir) => dir.to_owned(),
+      None => PathBuf::new(),
+    },
+    None => PathBuf::new(),
+  }
+}
+
+// the value of a plain or raw string literal, written the way to_string
+// gives it back
+fn string_value(literal: &str) -> Option<String> {
+  if let Some(raw) = literal.strip_prefix('r') {
+    let hashes = raw.len() - raw.trim_start_matches('#').len();
+    let inner = &raw[hashes..raw.len().checked_sub(hashes)?];
+    return inner.strip_prefix('"')?.strip_suffix('"').map(|s| s.to_owned());
+  }
+  let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+  let mut ret = String::new();
+  let mut chars = inner.chars();
+  while let Some(ch) = chars.next() {
+    if ch == '\\' {
+      match chars.next()? {
+        '\\' => ret.push('\\'),
+        '"' => ret.push('"'),
+        '\'' => ret.push('\''),
+        'n' => ret.push('\n'),
+        't' => ret.push('\t'),
+        _ => return None,
+      }
+    } else {
+      ret.push(ch);
+    }
+  }
+  Some(ret)
+}
+
+fn expand(program: &Program) -> TokenStream {
+  let mut out = format!(
+    "pub const PROGRAM: [u16; {}] = [", program.image.len(),
+  );
+  for word in &program.image {
+    out.push_str(&format!("0x{:04X}, ", word));
+  }
+  out.push_str("];\n");
+
+  // so that cargo rebuilds when an assembly file changes
+  for source in program.parser.sources() {
+    if source.ends_with(INLINE_NAME) {
+      continue;
+    }
+    if let Some(source) = source.to_str() {
+      out.push_str(&format!(
+        "const _: &[u8] = include_bytes!({:?});\n", source,
+      ));
+    }
+  }
+
+  out.push_str("#[allow(non_upper_case_globals)]\npub mod symbols {\n");
+  // names which aren't identifiers are mangled, like `Point.x' to
+  // `Point_x'; if that makes two the same, the first wins
+  let mut names = Vec::new();
+  for (name, value, _, _) in program.parser.symbol_table() {
+    let name = Language::Rust.identifier(name);
+    if !names.contains(&name) {
+      out.push_str(&format!("  pub const {}: u16 = 0x{:04X};\n", name, value));
+      names.push(name);
+    }
+  }
+  out.push_str("}\n");
+  out.parse().unwrap()
+}
+
+fn describe(diagnostic: &Diagnostic) -> String {
+  match diagnostic.position {
+    Some((ref file, line)) => {
+      format!("{} at {}:{}", diagnostic.message, file, line)
+    },
+    None => diagnostic.message.clone(),
+  }
+}
+
+// warnings and notes, the way the assembler prints them; rustc shows what
+// a proc macro prints. `span' is where the diagnostic is in the rust source
+fn print(diagnostic: &Diagnostic, span: Option<Span>) {
+  match span.and_then(|span| Some((span.local_file()?, span.line()))) {
+    Some((file, line)) => eprintln!(
+      "{}: {} at {}:{}",
+      diagnostic.level, diagnostic.message, file.display(), line,
+    ),
+    None => eprintln!("{}: {}", diagnostic.level, describe(diagnostic)),
+  }
+}
+
+// compile_error!("message"), pointing at `span'
+fn compile_error(message: &str, span: Span) -> TokenStream {
+  let mut bang = Punct::new('!', Spacing::Alone);
+  bang.set_span(span);
+  let mut message = Literal::string(message);
+  message.set_span(span);
+  let mut args = Group::new(
+    Delimiter::Parenthesis, TokenTree::Literal(message).into(),
+  );
+  args.set_span(span);
+  let mut semi = Punct::new(';', Spacing::Alone);
+  semi.set_span(span);
+  vec![
+    TokenTree::Ident(Ident::new("compile_error", span)),
+    TokenTree::Punct(bang),
+    TokenTree::Group(args),
+    TokenTree::Punct(semi),
+  ].into_iter().collect()
+}
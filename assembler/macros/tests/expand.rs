@@ -0,0 +1,69 @@
+// expands ct64k_asm! and ct64k_include!, and checks that they give what the
+// assembler does
+
+extern crate assembler;
+#[macro_use]
+extern crate ct64k_macros;
+
+use std::path::Path;
+
+use assembler::macros::catch_errors;
+use assembler::parser::Options;
+use assembler::Program;
+
+mod inline {
+  ct64k_asm! {
+    public equ COUNT 3
+    // a comment
+    main:
+      mi 0x80, COUNT
+      mi 0x81, table
+      hf
+    table: data 1, 2, COUNT
+    loop: data 0
+  }
+}
+
+mod included {
+  ct64k_include!("hello.asm");
+}
+
+fn assemble(path: &Path, source: &str) -> Program {
+  match catch_errors(|| {
+    Program::from_source(path, source, &Options::default())
+  }).0 {
+    Ok(program) => program,
+    Err(error) => panic!("{}", error.message),
+  }
+}
+
+#[test]
+fn ct64k_asm_assembles_inline() {
+  let program = assemble(Path::new("inline.asm"), "\
+public equ COUNT 3
+main:
+  mi 0x80, COUNT
+  mi 0x81, table
+  hf
+table: data 1, 2, COUNT
+loop: data 0
+");
+  assert_eq!(&inline::PROGRAM[..], &program.image[..]);
+  assert_eq!(inline::symbols::main, 0x1000);
+  assert_eq!(inline::symbols::table, program.parser.label("table").unwrap());
+  assert_eq!(inline::symbols::r#loop, inline::symbols::table + 3);
+  assert_eq!(inline::symbols::COUNT, 3);
+}
+
+#[test]
+fn ct64k_include_assembles_a_file() {
+  let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/hello.asm");
+  let source = std::fs::read_to_string(&path).unwrap();
+  let program = assemble(&path, &source);
+  assert_eq!(&included::PROGRAM[..], &program.image[..]);
+  assert_eq!(included::symbols::msg, program.parser.label("msg").unwrap());
+  assert_eq!(
+    &included::PROGRAM[(included::symbols::msg - 0x1000) as usize..],
+    [u16::from(b'h'), u16::from(b'i')],
+  );
+}
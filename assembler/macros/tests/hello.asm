@@ -0,0 +1,5 @@
+; what expand.rs includes with ct64k_include!
+main:
+  mi 0x80, msg
+  hf
+msg: data "hi"
@@ -0,0 +1,181 @@
+// the CT64k executable format. everything is little endian:
+//   magic     "CT6K"
+//   version   u16; FORMAT_VERSION
+//   entry     u16; where ip starts
+//   stack     u16; where sp and bp start
+//   segments  u16; how many follow the header
+//   symbols   u16; how many follow the segments
+//   checksum  u16; crc-16 of every word after the header
+// each segment is:
+//   address   u16
+//   length    u16; in words
+//   kind      u16; SEGMENT_DATA, followed by `length' words, or SEGMENT_ZERO
+// and each symbol is:
+//   value     u16
+//   length    u16; of the name, in bytes
+//   name      utf-8, padded to a whole word
+
+use lexer::Checksum;
+
+const MAGIC: &[u8; 4] = b"CT6K";
+const FORMAT_VERSION: u16 = 1;
+// after the magic
+const HEADER_WORDS: usize = 6;
+
+const SEGMENT_DATA: u16 = 0;
+const SEGMENT_ZERO: u16 = 1;
+
+pub enum Contents {
+  Data(Vec<u16>),
+  // zeroed by the loader, and not stored
+  Zero(u16),
+}
+
+pub struct Segment {
+  pub address: u16,
+  pub contents: Contents,
+}
+
+impl Segment {
+  pub fn len(&self) -> u16 {
+    match self.contents {
+      Contents::Data(ref data) => data.len() as u16,
+      Contents::Zero(len) => len,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+pub struct Executable {
+  pub entry: u16,
+  pub stack: u16,
+  pub segments: Vec<Segment>,
+  pub symbols: Vec<(String, u16)>,
+}
+
+impl Executable {
+  pub fn write(&self) -> Vec<u8> {
+    let mut body = Vec::new();
+    for segment in &self.segments {
+      body.push(segment.address);
+      body.push(segment.len());
+      match segment.contents {
+        Contents::Data(ref data) => {
+          body.push(SEGMENT_DATA);
+          body.extend_from_slice(data);
+        },
+        Contents::Zero(_) => body.push(SEGMENT_ZERO),
+      }
+    }
+    for &(ref name, value) in &self.symbols {
+      body.push(value);
+      body.push(name.len() as u16);
+      body.extend(name.as_bytes().chunks(2).map(|pair| match *pair {
+        [lo, hi] => u16::from_le_bytes([lo, hi]),
+        [lo] => lo as u16,
+        _ => unreachable!(),
+      }));
+    }
+
+    let mut out = MAGIC.to_vec();
+    let header = [
+      FORMAT_VERSION,
+      self.entry,
+      self.stack,
+      self.segments.len() as u16,
+      self.symbols.len() as u16,
+      Checksum::Crc16.compute(&body),
+    ];
+    for word in header.iter().chain(&body) {
+      out.extend_from_slice(&word.to_le_bytes());
+    }
+    out
+  }
+
+  pub fn read(bytes: &[u8]) -> Result<Executable, String> {
+    if bytes.len() < MAGIC.len() + 2 * HEADER_WORDS || &bytes[..4] != MAGIC {
+      return Err("Not a CT64k executable".to_owned());
+    }
+    if !bytes.len().is_multiple_of(2) {
+      return Err("Truncated executable".to_owned());
+    }
+    let words = bytes[4..].chunks(2)
+      .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+      .collect::<Vec<_>>();
+    if words[0] != FORMAT_VERSION {
+      return Err(format!("Unsupported format version: {}", words[0]));
+    }
+    let (entry, stack, segments, symbols, checksum) =
+      (words[1], words[2], words[3], words[4], words[5]);
+    let mut body = Words {
+      words: &words[HEADER_WORDS..],
+      idx: 0,
+    };
+    if Checksum::Crc16.compute(body.words) != checksum {
+      return Err("Checksum mismatch; the executable is corrupt".to_owned());
+    }
+
+    let segments = (0..segments).map(|_| {
+      let address = body.next()?;
+      let len = body.next()?;
+      let contents = match body.next()? {
+        SEGMENT_DATA => Contents::Data(body.take(len as usize)?.to_vec()),
+        SEGMENT_ZERO => Contents::Zero(len),
+        kind => return Err(format!("Unknown segment kind: {}", kind)),
+      };
+      if address as usize + len as usize > 0x10000 {
+        return Err(format!("Segment at 0x{:04X} runs off the end", address));
+      }
+      Ok(Segment {
+        address: address,
+        contents: contents,
+      })
+    }).collect::<Result<Vec<_>, _>>()?;
+    let symbols = (0..symbols).map(|_| {
+      let value = body.next()?;
+      let len = body.next()? as usize;
+      let mut name = body.take(len.div_ceil(2))?.iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+      name.truncate(len);
+      match String::from_utf8(name) {
+        Ok(name) => Ok((name, value)),
+        Err(_) => Err("Invalid utf8 in a symbol".to_owned()),
+      }
+    }).collect::<Result<Vec<_>, _>>()?;
+    if body.idx != body.words.len() {
+      return Err("Trailing data after the symbols".to_owned());
+    }
+
+    Ok(Executable {
+      entry: entry,
+      stack: stack,
+      segments: segments,
+      symbols: symbols,
+    })
+  }
+}
+
+struct Words<'a> {
+  words: &'a [u16],
+  idx: usize,
+}
+
+impl<'a> Words<'a> {
+  fn next(&mut self) -> Result<u16, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u16], String> {
+    match self.words.get(self.idx..self.idx + n) {
+      Some(words) => {
+        self.idx += n;
+        Ok(words)
+      },
+      None => Err("Truncated executable".to_owned()),
+    }
+  }
+}
@@ -0,0 +1,513 @@
+// the `debug' subcommand: runs a program in emulator::Machine, behind a
+// REPL which knows which line of source each instruction came from
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use emulator::{Machine, REG_SP};
+use parser::{Parser, INST_OFFSET_BASE};
+use {parse_number, Program};
+
+const HELP: &str = "\
+run, r                 start the program again, and continue
+continue, c            run until a breakpoint, or hf
+step, s [N]            run until the next line of source
+next, n [N]            like step, but runs over a `call' or `callm'
+finish, fin            run until the current procedure's `ret'
+stepi, si [N]          run one instruction
+break, b LOC           stop at LOC, which can also be FILE:LINE
+delete, d [N]          remove breakpoint N, or all of them
+info breakpoints       list the breakpoints
+info registers         print ip, sp, bp and sc0 to sc3
+print, p[/F] LOC       print the word at LOC
+x[/NF] LOC             print N words from LOC
+disassemble [LOC] [N]  print N instructions from LOC, with their source
+help, h                print this
+quit, q                leave the debugger
+
+LOC is a label, an address, or *LOC for the word LOC points at; `p sp'
+is the stack pointer, and `x/4x *sp' the top of the stack. F is x for
+hex, c for a character, or i for an instruction. An empty line repeats
+the last command.";
+
+const REGISTERS: &[&str] = &["ip", "sp", "bp", "sc0", "sc1", "sc2", "sc3"];
+
+// how far step runs
+#[derive(Copy, Clone, PartialEq)]
+pub enum Run {
+  // until a breakpoint, or hf
+  Continue,
+  // until the next line of source
+  Step,
+  // like Step, but over a `call' or `callm'
+  Next,
+  // until the current procedure's `ret'
+  Finish,
+  // one instruction
+  StepInstruction,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Stop {
+  Done,
+  // the breakpoint's number
+  Breakpoint(usize),
+  Halted,
+}
+
+struct Debugger<'a> {
+  program: &'a Program,
+  stack: u16,
+  machine: Machine,
+  halted: bool,
+  // numbered from 1; deleted ones are left as None, so the numbers stay
+  breakpoints: Vec<Option<u16>>,
+  // the lines of each source file, or None if it can't be read
+  files: HashMap<String, Option<Vec<String>>>,
+}
+
+// `stack' is where sp starts, as the crt0 stub would have it
+pub fn run(program: &Program, stack: u16) {
+  let mut debugger = Debugger {
+    program: program,
+    stack: stack,
+    machine: match Debugger::machine(program, stack) {
+      Ok(machine) => machine,
+      Err(e) => error_np!("{}", e),
+    },
+    halted: false,
+    breakpoints: Vec::new(),
+    files: HashMap::new(),
+  };
+  debugger.show_location();
+
+  let stdin = io::stdin();
+  let mut last = String::new();
+  loop {
+    print!("(ct64k) ");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    match stdin.lock().read_line(&mut line) {
+      Ok(0) | Err(_) => break,
+      Ok(_) => {},
+    }
+    let line = line.trim();
+    if !line.is_empty() {
+      last = line.to_owned();
+    }
+    if last.is_empty() {
+      continue;
+    }
+    match debugger.command(&last.clone()) {
+      Ok(true) => {},
+      Ok(false) => break,
+      Err(e) => println!("{}", e),
+    }
+  }
+}
+
+impl<'a> Debugger<'a> {
+  fn machine(program: &Program, stack: u16) -> Result<Machine, String> {
+    Machine::new(
+      &program.image, stack, Box::new(io::stdin()), Box::new(io::stdout()),
+    )
+  }
+
+  // false to quit
+  fn command(&mut self, line: &str) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let head = words.next().unwrap();
+    let (name, format) = match head.find('/') {
+      Some(idx) => (&head[..idx], &head[idx + 1..]),
+      None => (head, ""),
+    };
+    let args = words.collect::<Vec<_>>();
+    let count = || match args.first() {
+      Some(n) => match parse_number(n) {
+        Some(n) => Ok(n as usize),
+        None => Err(format!("Not a count: {}", n)),
+      },
+      None => Ok(1),
+    };
+    let arg = || match args.first() {
+      Some(&arg) => Ok(arg),
+      None => Err(format!("`{}' needs a location", name)),
+    };
+
+    match name {
+      "help" | "h" => println!("{}", HELP),
+      "quit" | "q" => return Ok(false),
+      "run" | "r" => {
+        self.machine = Debugger::machine(self.program, self.stack)?;
+        self.halted = false;
+        self.resume(Run::Continue, 1)?;
+      },
+      "continue" | "c" => self.resume(Run::Continue, 1)?,
+      "step" | "s" => self.resume(Run::Step, count()?)?,
+      "next" | "n" => self.resume(Run::Next, count()?)?,
+      "finish" | "fin" => self.resume(Run::Finish, 1)?,
+      "stepi" | "si" => self.resume(Run::StepInstruction, count()?)?,
+      "break" | "b" => {
+        let address = self.location(arg()?)?;
+        self.breakpoints.push(Some(address));
+        println!(
+          "Breakpoint {} at {}{}",
+          self.breakpoints.len(),
+          self.describe(address),
+          self.line_name(address).map(|s| format!(": {}", s))
+            .unwrap_or_default(),
+        );
+      },
+      "delete" | "d" => match args.first() {
+        Some(n) => match n.parse::<usize>() {
+          Ok(n) if n > 0 && n <= self.breakpoints.len() => {
+            self.breakpoints[n - 1] = None;
+          },
+          _ => return Err(format!("No breakpoint {}", n)),
+        },
+        None => {
+          for breakpoint in &mut self.breakpoints {
+            *breakpoint = None;
+          }
+        },
+      },
+      "info" | "i" => match args.first().cloned() {
+        Some("breakpoints") | Some("b") => {
+          for (n, breakpoint) in self.breakpoints.iter().enumerate() {
+            if let Some(address) = *breakpoint {
+              println!("{}  {}", n + 1, self.describe(address));
+            }
+          }
+        },
+        Some("registers") | Some("r") => {
+          for (address, name) in REGISTERS.iter().enumerate() {
+            println!("{:<4} 0x{:04X}", name, self.machine.memory[address]);
+          }
+        },
+        _ => return Err("`info' takes breakpoints or registers".to_owned()),
+      },
+      "print" | "p" => {
+        let (_, format) = Debugger::format(format)?;
+        let address = self.location(arg()?)?;
+        let value = self.machine.memory[address as usize];
+        match format {
+          'i' => println!("{} = {}", arg()?, self.instruction(address).0),
+          format => {
+            println!("{} = {}", arg()?, Debugger::value(value, format))
+          },
+        }
+      },
+      "x" => {
+        let (count, format) = Debugger::format(format)?;
+        let mut address = self.location(arg()?)?;
+        if format == 'i' {
+          for _ in 0..count {
+            let (text, size) = self.instruction(address);
+            println!("{}:  {}", self.describe(address), text);
+            address = address.wrapping_add(size);
+          }
+        } else {
+          for row in 0..count.div_ceil(8) {
+            let mut line = format!("{}:", self.describe(address));
+            for _ in 0..(count - row * 8).min(8) {
+              let value = self.machine.memory[address as usize];
+              line.push_str(&format!("  {}", Debugger::value(value, format)));
+              address = address.wrapping_add(1);
+            }
+            println!("{}", line);
+          }
+        }
+      },
+      "disassemble" | "disas" => {
+        let address = match args.first() {
+          Some(loc) => self.location(loc)?,
+          // from the start of the current line
+          None => match self.line_index(self.machine.ip()) {
+            Some(idx) => self.program.parser.lines()[idx].start,
+            None => self.machine.ip(),
+          },
+        };
+        let count = match args.get(1) {
+          Some(n) => match parse_number(n) {
+            Some(n) => n as usize,
+            None => return Err(format!("Not a count: {}", n)),
+          },
+          None => 10,
+        };
+        self.disassemble(address, count);
+      },
+      _ => return Err(format!("Unknown command: {}; try `help'", name)),
+    }
+    Ok(true)
+  }
+
+  // `count' times, unless something stops it first
+  fn resume(&mut self, run: Run, count: usize) -> Result<(), String> {
+    if self.halted {
+      return Err("The program has halted; `run' starts it again".to_owned());
+    }
+    let mut stop = Stop::Done;
+    for _ in 0..count {
+      stop = step(self.program, &mut self.machine, &self.breakpoints, run);
+      if let Stop::Done = stop {
+        continue;
+      }
+      break;
+    }
+    let ip = self.machine.ip();
+    match stop {
+      Stop::Done => {},
+      Stop::Breakpoint(n) => {
+        println!("Breakpoint {}, {}", n, self.describe(ip))
+      },
+      Stop::Halted => {
+        self.halted = true;
+        println!("Halted at {}", self.describe(ip));
+      },
+    }
+    self.show_location();
+    Ok(())
+  }
+
+  // a label, FILE:LINE, an address, or *LOC
+  fn location(&self, loc: &str) -> Result<u16, String> {
+    if let Some(pointer) = loc.strip_prefix('*') {
+      let address = self.location(pointer)?;
+      return Ok(self.machine.memory[address as usize]);
+    }
+    if let Some(idx) = loc.rfind(':') {
+      if let Ok(line) = loc[idx + 1..].parse::<usize>() {
+        let file = Path::new(&loc[..idx]);
+        return self.program.parser.lines().iter()
+          .filter(|source| {
+            source.start < source.end && source.pos.line == line
+              && Path::new(source.pos.file()).ends_with(file)
+          })
+          .map(|source| source.start)
+          .min()
+          .ok_or_else(|| format!("No instructions at {}", loc));
+      }
+    }
+    match parse_number(loc) {
+      Some(address) => Ok(address),
+      None => self.program.parser.label(loc)
+        .ok_or_else(|| format!("No label or address: {}", loc)),
+    }
+  }
+
+  // [count]format, like 4x
+  fn format(format: &str) -> Result<(usize, char), String> {
+    let digits = format.len() - format.trim_start_matches(|ch: char| {
+      ch.is_ascii_digit()
+    }).len();
+    let count = match &format[..digits] {
+      "" => 1,
+      count => match count.parse() {
+        Ok(count) => count,
+        Err(_) => return Err(format!("Not a count: {}", count)),
+      },
+    };
+    match &format[digits..] {
+      "" => Ok((count, 'x')),
+      "x" => Ok((count, 'x')),
+      "c" => Ok((count, 'c')),
+      "i" => Ok((count, 'i')),
+      other => Err(format!("Unknown format: {}; use x, c or i", other)),
+    }
+  }
+
+  fn value(value: u16, format: char) -> String {
+    if format != 'c' {
+      return format!("0x{:04X}", value);
+    }
+    match value {
+      0x00 => "'\\0'".to_owned(),
+      0x09 => "'\\t'".to_owned(),
+      0x0A => "'\\n'".to_owned(),
+      0x0D => "'\\r'".to_owned(),
+      0x20..=0x7E => format!("'{}'", value as u8 as char),
+      0x01..=0xFF => format!("'\\x{:02X}'", value),
+      _ => format!("0x{:04X}", value),
+    }
+  }
+
+  // the instruction at `address', and how long it is
+  fn instruction(&self, address: u16) -> (String, u16) {
+    use OpcodeVariant::*;
+    let (op, size) = self.machine.decode(address);
+    let text = match op.var {
+      _ if self.machine.is_halt(address) => "hf".to_owned(),
+      JumpGreater(label) | JumpLesser(label) | JumpEqual(label) => format!(
+        "{} 0x{:03X}, 0x{:04X}, {}",
+        op.mnemonic(), op.reg, op.num, self.describe(label),
+      ),
+      _ => format!("{} 0x{:03X}, 0x{:04X}", op.mnemonic(), op.reg, op.num),
+    };
+    (text, size)
+  }
+
+  // `count' instructions, or data words where there's no source, with
+  // the lines they came from, and the macros
+  fn disassemble(&mut self, mut address: u16, count: usize) {
+    let mut last = None;
+    for _ in 0..count {
+      for (name, _) in self.labels().filter(|&(_, value)| value == address) {
+        println!("{}:", name);
+      }
+      let idx = self.line_index(address);
+      if idx != last {
+        if let Some(name) = self.line_name(address) {
+          println!("{}", name);
+        }
+        last = idx;
+      }
+      let (text, size, op) = match idx {
+        Some(idx) => {
+          let (text, size) = self.instruction(address);
+          (text, size, Some(&self.program.parser.lines()[idx].op))
+        },
+        None => {
+          let value = self.machine.memory[address as usize];
+          (format!("data 0x{:04X}", value), 1, None)
+        },
+      };
+      let marker = if address == self.machine.ip() {
+        "=>"
+      } else if self.breakpoints.contains(&Some(address)) {
+        " *"
+      } else {
+        "  "
+      };
+      // which macro the instruction is part of
+      let mnemonic = text.split(' ').next().unwrap();
+      match op {
+        Some(op) if op != mnemonic => {
+          println!("{} 0x{:04X}  {:<30} ; {}", marker, address, text, op)
+        },
+        _ => println!("{} 0x{:04X}  {}", marker, address, text),
+      }
+      address = address.wrapping_add(size);
+    }
+  }
+
+  fn show_location(&mut self) {
+    let ip = self.machine.ip();
+    if let Some(name) = self.line_name(ip) {
+      println!("{}", name);
+    }
+    let (text, _) = self.instruction(ip);
+    println!("=> {}:  {}", self.describe(ip), text);
+  }
+
+  // the labels which describe addresses, rather than constants
+  fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+    self.program.parser.symbols().into_iter()
+      .filter(|&(_, value)| value >= INST_OFFSET_BASE)
+  }
+
+  // 0x1004 <main+0x4>
+  fn describe(&self, address: u16) -> String {
+    let label = self.labels().filter(|&(_, value)| value <= address).last();
+    match label {
+      Some((name, value)) if value == address => {
+        format!("0x{:04X} <{}>", address, name)
+      },
+      Some((name, value)) => {
+        format!("0x{:04X} <{}+0x{:X}>", address, name, address - value)
+      },
+      None => format!("0x{:04X}", address),
+    }
+  }
+
+  fn line_index(&self, address: u16) -> Option<usize> {
+    line_index(self.program, address)
+  }
+
+  // file.asm:12  mi r00, 0x41
+  fn line_name(&mut self, address: u16) -> Option<String> {
+    let idx = self.line_index(address)?;
+    let pos = &self.program.parser.lines()[idx].pos;
+    let file = pos.file().to_owned();
+    let short = Path::new(&file).file_name()
+      .and_then(|name| name.to_str())
+      .unwrap_or(&file)
+      .to_owned();
+    let lines = self.files.entry(file.clone()).or_insert_with(|| {
+      let source = match Parser::runtime_source(&file) {
+        Some(source) => Some(source.to_owned()),
+        None => ::std::fs::read_to_string(&file).ok(),
+      };
+      source.map(|source| source.lines().map(|l| l.to_owned()).collect())
+    });
+    let text = match *lines {
+      Some(ref lines) if pos.line > 0 => {
+        lines.get(pos.line - 1).map(|line| line.trim()).unwrap_or("")
+      },
+      _ => "",
+    };
+    Some(format!("{}:{}  {}", short, pos.line, text))
+  }
+}
+
+// runs `machine', which is running `program', as far as `run' says, or to
+// the first of `breakpoints' it reaches, other than the one at ip
+pub fn step(
+  program: &Program, machine: &mut Machine, breakpoints: &[Option<u16>],
+  run: Run,
+) -> Stop {
+  // the range of the line that's running, and whether it's a call
+  let (start, end, is_call) = match line_index(program, machine.ip()) {
+    Some(idx) => {
+      let line = &program.parser.lines()[idx];
+      (line.start, line.end, line.op == "call" || line.op == "callm")
+    },
+    None => (machine.ip(), machine.ip(), false),
+  };
+  let stack = machine.memory[REG_SP as usize];
+  let mut first = true;
+  loop {
+    let ip = machine.ip();
+    if !first {
+      let breakpoint = breakpoints.iter()
+        .position(|&breakpoint| breakpoint == Some(ip));
+      if let Some(n) = breakpoint {
+        return Stop::Breakpoint(n + 1);
+      }
+    }
+    first = false;
+    if !machine.step() {
+      return Stop::Halted;
+    }
+
+    let next = machine.ip();
+    let sp = machine.memory[REG_SP as usize];
+    let left = next < start || next >= end;
+    let done = match run {
+      Run::Continue => false,
+      Run::StepInstruction => true,
+      Run::Next if is_call => next == end && sp <= stack,
+      Run::Step | Run::Next => left,
+      // a ret which popped this procedure's return address
+      Run::Finish => match line_index(program, ip) {
+        Some(idx) => {
+          let line = &program.parser.lines()[idx];
+          line.op == "ret" && (next < line.start || next >= line.end)
+            && sp < stack
+        },
+        None => false,
+      },
+    };
+    if done {
+      return Stop::Done;
+    }
+  }
+}
+
+// which line the instruction at `address' came from
+pub fn line_index(program: &Program, address: u16) -> Option<usize> {
+  let lines = program.parser.lines();
+  let end = lines.partition_point(|line| line.start <= address);
+  lines[..end].iter().rposition(|line| line.start < line.end)
+    .filter(|&idx| address < lines[idx].end)
+}
@@ -0,0 +1,374 @@
+// ELF32 output, for readelf, objdump and nm. the CT64k is word addressed,
+// but ELF counts in bytes; so everything in the file is in bytes, and an
+// address is twice the word address. that covers the entry, the segment and
+// section addresses, and the labels, which are in a section; equs are
+// absolute symbols, and are left as they are
+
+use container::{Contents, Executable, Segment};
+use parser::SymbolKind;
+
+// from the range ELF leaves unassigned; there's no official number
+const EM_CT64K: u16 = 0x6B64;
+
+const ELF_HEADER_SIZE: u32 = 52;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+const SECTION_HEADER_SIZE: u32 = 40;
+const SYMBOL_SIZE: u32 = 16;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHN_ABS: u16 = 0xFFF1;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+
+// the section indices write uses
+const SECTION_TEXT: u16 = 1;
+const SECTION_DATA: u16 = 2;
+const SECTION_BSS: u16 = 3;
+
+// the ELF address of the word at `address'
+fn byte_address(address: u16) -> u32 {
+  2 * address as u32
+}
+
+#[derive(Default)]
+struct SectionHeader {
+  name: u32,
+  kind: u32,
+  flags: u32,
+  address: u32,
+  offset: u32,
+  size: u32,
+  link: u32,
+  info: u32,
+  align: u32,
+  entsize: u32,
+}
+
+pub struct Layout {
+  pub base: u16,
+  pub entry: u16,
+  // the image is .text up to text_end, and .data after it
+  pub text_end: u16,
+  // (start, size) of .bss
+  pub bss: (u16, u16),
+}
+
+// symbols are (name, value, public, kind); public ones are STB_GLOBAL
+pub fn write(
+  image: &[u16], layout: &Layout, symbols: &[(&str, u16, bool, SymbolKind)],
+) -> Vec<u8> {
+  fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+  }
+  fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+  }
+  fn string(table: &mut Vec<u8>, s: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(s.as_bytes());
+    table.push(0);
+    offset
+  }
+
+  let text_len = (layout.text_end - layout.base) as usize;
+  let (text, data) = image.split_at(text_len);
+  let data_start = layout.text_end;
+  let (bss_start, bss_size) = layout.bss;
+  // a label just past the end of the program is in its last section
+  let section_of = |value: u16| {
+    let value = value as u32;
+    let data_end = data_start as u32 + data.len() as u32;
+    let bss_end = bss_start as u32 + bss_size as u32;
+    if value >= layout.base as u32 && value < data_start as u32 {
+      SECTION_TEXT
+    } else if value >= data_start as u32 && value < data_end {
+      SECTION_DATA
+    } else if (value >= bss_start as u32 && value < bss_end)
+      || (bss_size != 0 && value == bss_end) {
+      SECTION_BSS
+    } else if !data.is_empty() && value == data_end {
+      SECTION_DATA
+    } else {
+      SECTION_TEXT
+    }
+  };
+
+  // locals have to come before globals
+  let mut symbols = symbols.to_vec();
+  symbols.sort_by_key(|&(_, _, public, _)| public);
+  let mut strtab = vec![0];
+  let mut symtab = vec![0; SYMBOL_SIZE as usize];
+  for &(name, value, public, kind) in &symbols {
+    let name = string(&mut strtab, name);
+    put32(&mut symtab, name);
+    let section = match kind {
+      SymbolKind::Label => {
+        put32(&mut symtab, byte_address(value));
+        section_of(value)
+      },
+      SymbolKind::Equ => {
+        put32(&mut symtab, value as u32);
+        SHN_ABS
+      },
+    };
+    put32(&mut symtab, 0);
+    symtab.push(if public { STB_GLOBAL } else { STB_LOCAL } << 4);
+    symtab.push(0);
+    put16(&mut symtab, section);
+  }
+  let first_global = 1 + symbols.iter().filter(|s| !s.2).count() as u32;
+
+  let mut shstrtab = vec![0];
+  let names = [
+    ".text", ".data", ".bss", ".symtab", ".strtab", ".shstrtab",
+  ].iter().map(|name| string(&mut shstrtab, name)).collect::<Vec<_>>();
+
+  let phnum = [!text.is_empty(), !data.is_empty(), bss_size != 0].iter()
+    .filter(|&&present| present)
+    .count() as u32;
+  let text_offset = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * phnum;
+  let data_offset = text_offset + 2 * text.len() as u32;
+  let symtab_offset = data_offset + 2 * data.len() as u32;
+  let strtab_offset = symtab_offset + symtab.len() as u32;
+  let shstrtab_offset = strtab_offset + strtab.len() as u32;
+  // section headers are aligned to a word, for readers which care
+  let shoff = (shstrtab_offset + shstrtab.len() as u32 + 3) & !3;
+
+  // (address, offset, words in the file, words in memory, flags)
+  let mut loads = Vec::new();
+  if !text.is_empty() {
+    let len = text.len() as u32;
+    loads.push((layout.base, text_offset, len, len, PF_R | PF_X));
+  }
+  if !data.is_empty() {
+    let len = data.len() as u32;
+    loads.push((data_start, data_offset, len, len, PF_R | PF_W));
+  }
+  if bss_size != 0 {
+    loads.push((bss_start, 0, 0, bss_size as u32, PF_R | PF_W));
+  }
+
+  let sections = [
+    SectionHeader::default(),
+    SectionHeader {
+      name: names[0],
+      kind: SHT_PROGBITS,
+      flags: SHF_ALLOC | SHF_EXECINSTR,
+      address: byte_address(layout.base),
+      offset: text_offset,
+      size: 2 * text.len() as u32,
+      align: 2,
+      ..SectionHeader::default()
+    },
+    SectionHeader {
+      name: names[1],
+      kind: SHT_PROGBITS,
+      flags: SHF_ALLOC | SHF_WRITE,
+      address: byte_address(data_start),
+      offset: data_offset,
+      size: 2 * data.len() as u32,
+      align: 2,
+      ..SectionHeader::default()
+    },
+    SectionHeader {
+      name: names[2],
+      kind: SHT_NOBITS,
+      flags: SHF_ALLOC | SHF_WRITE,
+      address: byte_address(bss_start),
+      offset: symtab_offset,
+      size: 2 * bss_size as u32,
+      align: 2,
+      ..SectionHeader::default()
+    },
+    SectionHeader {
+      name: names[3],
+      kind: SHT_SYMTAB,
+      offset: symtab_offset,
+      size: symtab.len() as u32,
+      // .strtab
+      link: 5,
+      info: first_global,
+      align: 4,
+      entsize: SYMBOL_SIZE,
+      ..SectionHeader::default()
+    },
+    SectionHeader {
+      name: names[4],
+      kind: SHT_STRTAB,
+      offset: strtab_offset,
+      size: strtab.len() as u32,
+      align: 1,
+      ..SectionHeader::default()
+    },
+    SectionHeader {
+      name: names[5],
+      kind: SHT_STRTAB,
+      offset: shstrtab_offset,
+      size: shstrtab.len() as u32,
+      align: 1,
+      ..SectionHeader::default()
+    },
+  ];
+
+  let mut out = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+  out.resize(16, 0);
+  put16(&mut out, ET_EXEC);
+  put16(&mut out, EM_CT64K);
+  put32(&mut out, 1);
+  put32(&mut out, byte_address(layout.entry));
+  put32(&mut out, ELF_HEADER_SIZE);
+  put32(&mut out, shoff);
+  put32(&mut out, 0);
+  put16(&mut out, ELF_HEADER_SIZE as u16);
+  put16(&mut out, PROGRAM_HEADER_SIZE as u16);
+  put16(&mut out, loads.len() as u16);
+  put16(&mut out, SECTION_HEADER_SIZE as u16);
+  put16(&mut out, sections.len() as u16);
+  put16(&mut out, sections.len() as u16 - 1);
+
+  for &(address, offset, file_words, memory_words, flags) in &loads {
+    put32(&mut out, PT_LOAD);
+    put32(&mut out, offset);
+    put32(&mut out, byte_address(address));
+    put32(&mut out, byte_address(address));
+    put32(&mut out, 2 * file_words);
+    put32(&mut out, 2 * memory_words);
+    put32(&mut out, flags);
+    put32(&mut out, 2);
+  }
+  for &word in text.iter().chain(data) {
+    put16(&mut out, word);
+  }
+  out.extend_from_slice(&symtab);
+  out.extend_from_slice(&strtab);
+  out.extend_from_slice(&shstrtab);
+  out.resize(shoff as usize, 0);
+  for section in &sections {
+    for &field in &[
+      section.name, section.kind, section.flags, section.address,
+      section.offset, section.size, section.link, section.info, section.align,
+      section.entsize,
+    ] {
+      put32(&mut out, field);
+    }
+  }
+  out
+}
+
+// loads what write makes, or anything else with CT64k PT_LOAD segments. ELF
+// has nowhere to put the stack, so it's left at `stack'
+pub fn read(bytes: &[u8], stack: u16) -> Result<Executable, String> {
+  let u16_at = |offset: usize| match bytes.get(offset..offset + 2) {
+    Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+    None => Err("Truncated ELF file".to_owned()),
+  };
+  let u32_at = |offset: usize| match bytes.get(offset..offset + 4) {
+    Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    None => Err("Truncated ELF file".to_owned()),
+  };
+
+  if !bytes.starts_with(b"\x7FELF") {
+    return Err("Not an ELF file".to_owned());
+  }
+  if bytes.get(4..6) != Some(&[1, 1]) {
+    return Err("Not a little endian ELF32 file".to_owned());
+  }
+  if u16_at(18)? != EM_CT64K {
+    return Err(format!("Not a CT64k ELF file; machine is 0x{:X}", u16_at(18)?));
+  }
+  let entry = u32_at(24)? / 2;
+  let phoff = u32_at(28)? as usize;
+  let shoff = u32_at(32)? as usize;
+  let phentsize = u16_at(42)? as usize;
+  let phnum = u16_at(44)? as usize;
+  let shentsize = u16_at(46)? as usize;
+  let shnum = u16_at(48)? as usize;
+
+  let mut segments = Vec::new();
+  for i in 0..phnum {
+    let header = phoff + i * phentsize;
+    if u32_at(header)? != PT_LOAD {
+      continue;
+    }
+    let offset = u32_at(header + 4)? as usize;
+    let address = u32_at(header + 8)?;
+    let file_size = u32_at(header + 16)? as usize;
+    let memory_size = u32_at(header + 20)? as usize;
+    if address % 2 != 0 {
+      return Err(format!("Segment at 0x{:X} isn't word aligned", address));
+    }
+    if (address / 2) as usize + memory_size / 2 > 0x10000 {
+      return Err(format!("Segment at 0x{:X} runs off the end", address));
+    }
+    let address = (address / 2) as u16;
+    if file_size != 0 {
+      let data = match bytes.get(offset..offset + file_size) {
+        Some(data) => data,
+        None => return Err("Truncated ELF file".to_owned()),
+      };
+      segments.push(Segment {
+        address: address,
+        contents: Contents::Data(data.chunks(2).map(|pair| {
+          u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)])
+        }).collect()),
+      });
+    }
+    if memory_size > file_size {
+      segments.push(Segment {
+        address: address + (file_size / 2) as u16,
+        contents: Contents::Zero(((memory_size - file_size) / 2) as u16),
+      });
+    }
+  }
+
+  let mut symbols = Vec::new();
+  for i in 0..shnum {
+    let header = shoff + i * shentsize;
+    if u32_at(header + 4)? != SHT_SYMTAB {
+      continue;
+    }
+    let offset = u32_at(header + 16)? as usize;
+    let size = u32_at(header + 20)? as usize;
+    let strtab = shoff + u32_at(header + 24)? as usize * shentsize;
+    let strtab = u32_at(strtab + 16)? as usize;
+    // the first symbol is always the null one
+    let entries = (offset..offset + size).step_by(SYMBOL_SIZE as usize);
+    for symbol in entries.skip(1) {
+      let name = strtab + u32_at(symbol)? as usize;
+      let name = match bytes.get(name..) {
+        Some(rest) => rest.split(|&b| b == 0).next().unwrap(),
+        None => return Err("Truncated ELF file".to_owned()),
+      };
+      let name = match String::from_utf8(name.to_vec()) {
+        Ok(name) => name,
+        Err(_) => return Err("Invalid utf8 in a symbol".to_owned()),
+      };
+      let value = u32_at(symbol + 4)?;
+      if u16_at(symbol + 14)? == SHN_ABS {
+        symbols.push((name, value as u16));
+      } else {
+        symbols.push((name, (value / 2) as u16));
+      }
+    }
+  }
+
+  Ok(Executable {
+    entry: entry as u16,
+    stack: stack,
+    segments: segments,
+    symbols: symbols,
+  })
+}
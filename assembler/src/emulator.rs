@@ -0,0 +1,190 @@
+// runs CT64k programs, with the semantics documented on OpcodeVariant
+
+use std::io::{Read, Write};
+
+use container::{Contents, Executable};
+use parser::INST_OFFSET_BASE;
+use {Opcode, OpcodeVariant};
+
+pub const REG_IP: u16 = 0x0;
+pub const REG_SP: u16 = 0x1;
+// writing a word puts its low byte out, and reading one takes a byte in;
+// 0xFFFF at the end of the input
+pub const STDOUT: u16 = 0x200;
+pub const STDIN: u16 = 0x201;
+
+pub struct Machine {
+  // all 64k words
+  pub memory: Vec<u16>,
+  input: Box<dyn Read>,
+  output: Box<dyn Write>,
+}
+
+impl Machine {
+  // `image' is loaded at INST_OFFSET_BASE, where ip starts; sp starts at
+  // `stack'. it's an error if the image doesn't fit
+  pub fn new(
+    image: &[u16], stack: u16, input: Box<dyn Read>, output: Box<dyn Write>,
+  ) -> Result<Self, String> {
+    let mut memory = vec![0; 0x10000];
+    let base = INST_OFFSET_BASE as usize;
+    if image.len() > memory.len() - base {
+      return Err(format!(
+        "The image is {} words; only 0x{:X} fit above 0x{:X}",
+        image.len(), memory.len() - base, base,
+      ));
+    }
+    memory[base..base + image.len()].copy_from_slice(image);
+    memory[REG_IP as usize] = INST_OFFSET_BASE;
+    memory[REG_SP as usize] = stack;
+    Ok(Machine {
+      memory: memory,
+      input: input,
+      output: output,
+    })
+  }
+
+  // `exe''s segments are loaded where they say, and ip and sp start at its
+  // entry and stack. it's an error if a segment runs off the end of memory
+  pub fn from_executable(
+    exe: &Executable, input: Box<dyn Read>, output: Box<dyn Write>,
+  ) -> Result<Self, String> {
+    let mut memory = vec![0; 0x10000];
+    for segment in &exe.segments {
+      let start = segment.address as usize;
+      let end = start + segment.len() as usize;
+      if end > memory.len() {
+        return Err(format!(
+          "The segment at 0x{:X} is {} words; it runs off the end of memory",
+          segment.address, segment.len(),
+        ));
+      }
+      match segment.contents {
+        Contents::Data(ref words) =>
+          memory[start..end].copy_from_slice(words),
+        Contents::Zero(_) => {
+          for word in &mut memory[start..end] {
+            *word = 0;
+          }
+        },
+      }
+    }
+    memory[REG_IP as usize] = exe.entry;
+    memory[REG_SP as usize] = exe.stack;
+    Ok(Machine {
+      memory: memory,
+      input: input,
+      output: output,
+    })
+  }
+
+  pub fn ip(&self) -> u16 {
+    self.memory[REG_IP as usize]
+  }
+
+  // the instruction at `address', and how many words it takes
+  pub fn decode(&self, address: u16) -> (Opcode, u16) {
+    Opcode::decode(&self.memory, address)
+  }
+
+  // whether the instruction at `address' is hf
+  pub fn is_halt(&self, address: u16) -> bool {
+    match self.decode(address).0 {
+      Opcode { var: OpcodeVariant::JumpEqual(label), reg, num } => {
+        reg == REG_IP && num == REG_IP && label == address
+      },
+      _ => false,
+    }
+  }
+
+  // runs the instruction at ip; returns false, without running it, if it's
+  // hf
+  pub fn step(&mut self) -> bool {
+    use OpcodeVariant::*;
+    let ip = self.ip();
+    if self.is_halt(ip) {
+      return false;
+    }
+    let (op, size) = self.decode(ip);
+    let (reg, num) = (op.reg, op.num);
+    self.memory[REG_IP as usize] = ip.wrapping_add(size);
+    match op.var {
+      MoveImmediate => self.write(reg, num),
+      Move => {
+        let value = self.read(num);
+        self.write(reg, value);
+      },
+      MoveDeref => {
+        let address = self.read(num);
+        let value = self.read(address);
+        self.write(reg, value);
+      },
+      Load => {
+        let address = self.read(reg);
+        let value = self.read(num);
+        self.write(address, value);
+      },
+      Store => {
+        let address = self.read(num);
+        let value = self.read(reg);
+        self.write(address, value);
+      },
+      Add => self.arith(reg, num, u16::wrapping_add),
+      Sub => self.arith(reg, num, u16::wrapping_sub),
+      And => self.arith(reg, num, |a, b| a & b),
+      Or => self.arith(reg, num, |a, b| a | b),
+      Xor => self.arith(reg, num, |a, b| a ^ b),
+      ShiftRight => {
+        self.arith(reg, num, |a, b| a.checked_shr(b as u32).unwrap_or(0))
+      },
+      ShiftLeft => {
+        self.arith(reg, num, |a, b| a.checked_shl(b as u32).unwrap_or(0))
+      },
+      ShiftArithmetic => {
+        self.arith(reg, num, |a, b| ((a as i16) >> b.min(15)) as u16)
+      },
+      JumpGreater(label) => {
+        if self.read(reg) > self.read(num) {
+          self.memory[REG_IP as usize] = label;
+        }
+      },
+      JumpLesser(label) => {
+        if self.read(reg) < self.read(num) {
+          self.memory[REG_IP as usize] = label;
+        }
+      },
+      JumpEqual(label) => {
+        if self.read(reg) == self.read(num) {
+          self.memory[REG_IP as usize] = label;
+        }
+      },
+      Data(_) => unreachable!("ICE: decoded an instruction as data"),
+    }
+    true
+  }
+
+  fn arith(&mut self, reg: u16, num: u16, f: fn(u16, u16) -> u16) {
+    let value = f(self.read(reg), self.read(num));
+    self.write(reg, value);
+  }
+
+  fn read(&mut self, address: u16) -> u16 {
+    if address == STDIN {
+      let mut byte = [0];
+      self.memory[STDIN as usize] = match self.input.read(&mut byte) {
+        Ok(1) => byte[0] as u16,
+        _ => 0xFFFF,
+      };
+    }
+    self.memory[address as usize]
+  }
+
+  fn write(&mut self, address: u16, value: u16) {
+    self.memory[address as usize] = value;
+    if address == STDOUT {
+      // the program's output is best effort, like the interpreter's
+      let _ = self.output.write_all(&[value as u8]);
+      let _ = self.output.flush();
+    }
+  }
+}
@@ -0,0 +1,126 @@
+// the public constants and labels, written out for host code: a C header,
+// a rust module of `pub const's, or a python module
+
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Language {
+  C,
+  Rust,
+  Python,
+}
+
+const C_KEYWORDS: &[&str] = &[
+  "auto", "break", "case", "char", "const", "continue", "default", "do",
+  "double", "else", "enum", "extern", "float", "for", "goto", "if",
+  "inline", "int", "long", "register", "restrict", "return", "short",
+  "signed", "sizeof", "static", "struct", "switch", "typedef", "union",
+  "unsigned", "void", "volatile", "while", "bool", "true", "false",
+];
+const RUST_KEYWORDS: &[&str] = &[
+  "as", "async", "await", "break", "const", "continue", "dyn", "else",
+  "enum", "extern", "false", "fn", "for", "gen", "if", "impl", "in", "let",
+  "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static",
+  "struct", "trait", "true", "try", "type", "unsafe", "use", "where",
+  "while", "abstract", "become", "box", "do", "final", "macro", "override",
+  "priv", "typeof", "unsized", "virtual", "yield",
+];
+// the ones which can't be raw identifiers
+const RUST_RESERVED: &[&str] = &["_", "self", "Self", "super", "crate"];
+const PYTHON_KEYWORDS: &[&str] = &[
+  "False", "None", "True", "and", "as", "assert", "async", "await",
+  "break", "class", "continue", "def", "del", "elif", "else", "except",
+  "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+  "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+  "with", "yield",
+];
+
+impl Language {
+  // by the extension; .h, .rs or .py
+  pub fn from_path(path: &Path) -> Option<Language> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("h") => Some(Language::C),
+      Some("rs") => Some(Language::Rust),
+      Some("py") => Some(Language::Python),
+      _ => None,
+    }
+  }
+
+  // `Point.x' becomes `Point_x', and keywords get a `_' after them, or an
+  // r# in front in rust
+  pub fn identifier(self, name: &str) -> String {
+    let mut ret = name.chars().map(|ch| {
+      if ch.is_ascii_alphanumeric() { ch } else { '_' }
+    }).collect::<String>();
+    if ret.starts_with(|ch: char| ch.is_ascii_digit()) {
+      ret.insert(0, '_');
+    }
+    match self {
+      Language::C if C_KEYWORDS.contains(&&*ret) => ret.push('_'),
+      Language::Rust if RUST_RESERVED.contains(&&*ret) => ret.push('_'),
+      Language::Rust if RUST_KEYWORDS.contains(&&*ret) => {
+        ret.insert_str(0, "r#")
+      },
+      Language::Python if PYTHON_KEYWORDS.contains(&&*ret) => ret.push('_'),
+      _ => {},
+    }
+    ret
+  }
+}
+
+// every name starts with `prefix', since a C header shares its names with
+// everything; `source' is the file the symbols came from, and `output' is
+// where this is going, for the include guard
+pub fn write(
+  language: Language,
+  symbols: &[(&str, u16)],
+  prefix: &str,
+  source: &str,
+  output: &Path,
+) -> String {
+  let mut names: Vec<(String, &str)> = Vec::new();
+  for &(name, _) in symbols {
+    let identifier = language.identifier(&format!("{}{}", prefix, name));
+    if let Some(&(_, other)) = names.iter().find(|n| n.0 == identifier) {
+      error_np!(
+        "`{}' and `{}' would both be exported as `{}'",
+        other, name, identifier,
+      );
+    }
+    names.push((identifier, name));
+  }
+
+  let mut out = String::new();
+  let comment = match language {
+    Language::C | Language::Rust => "//",
+    Language::Python => "#",
+  };
+  out.push_str(&format!(
+    "{} generated by the CT64k assembler from {}; don't edit\n",
+    comment, source,
+  ));
+  let guard = match output.file_name().and_then(|name| name.to_str()) {
+    Some(name) => Language::C.identifier(name).to_uppercase(),
+    None => "CT64K_EXPORTS_H".to_owned(),
+  };
+  match language {
+    Language::C => out.push_str(&format!(
+      "#ifndef {0}\n#define {0}\n\n", guard,
+    )),
+    Language::Rust => out.push_str("#![allow(non_upper_case_globals)]\n\n"),
+    Language::Python => out.push('\n'),
+  }
+  for (&(_, value), (identifier, _)) in symbols.iter().zip(&names) {
+    out.push_str(&match language {
+      Language::C => format!("#define {} 0x{:04X}\n", identifier, value),
+      Language::Rust => {
+        format!("pub const {}: u16 = 0x{:04X};\n", identifier, value)
+      },
+      Language::Python => format!("{} = 0x{:04X}\n", identifier, value),
+    });
+  }
+  if language == Language::C {
+    out.push_str(&format!("\n#endif // {}\n", guard));
+  }
+  out
+}
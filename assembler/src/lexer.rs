@@ -4,6 +4,9 @@ use std::rc::Rc;
 use std::fmt::{self, Display};
 use std::path::Path;
 
+// the binding level of `*' and `/'; see Lexer::binary
+const TERM_LEVEL: usize = 5;
+
 // index into the file vector
 #[derive(Copy, Clone, PartialEq)]
 pub struct File(u32);
@@ -15,13 +18,19 @@ pub struct __Files {
 
 impl PartialEq for __Files {
   fn eq(&self, other: &Self) -> bool {
-    &*self.hm as *const RefCell<_> == &*other.hm as *const RefCell<_>
+    ::std::ptr::eq(&*self.hm, &*other.hm)
   }
 }
 impl Eq for __Files {}
 #[derive(Clone, PartialEq, Eq)]
 pub struct Files(Option<__Files>);
 
+impl Default for Files {
+  fn default() -> Self {
+    Files::new()
+  }
+}
+
 impl Files {
   pub fn new() -> Self {
     let ret = Files(Some(__Files {
@@ -84,7 +93,6 @@ impl Position {
     }
   }
 
-  #[allow(unused)]
   pub fn file(&self) -> &str {
     self.files.get(self.file).unwrap()
   }
@@ -138,44 +146,196 @@ impl OpArg {
       },
       OpArgVar::MacroArg(n) =>
         mac_args[n as usize].evaluate(labels, &[], inst_offset),
-      OpArgVar::ArithOp(ref op, ref lhs, ref rhs) => op.op(
-        lhs.evaluate(labels, mac_args, inst_offset),
-        rhs.evaluate(labels, mac_args, inst_offset),
-      ),
+      OpArgVar::ArithOp(op, ref lhs, ref rhs) => {
+        let lhs = lhs.evaluate(labels, mac_args, inst_offset);
+        let rhs = rhs.evaluate(labels, mac_args, inst_offset);
+        if let ArithOp::Div = op {
+          if rhs == 0 {
+            error!(self.pos, "Division by zero");
+          }
+        }
+        op.op(lhs, rhs)
+      },
+      OpArgVar::Builtin(function, ref arg) =>
+        function.apply(arg.evaluate(labels, mac_args, inst_offset)),
       OpArgVar::Here => inst_offset,
+      OpArgVar::Immediate(ref value) => {
+        let value = value.evaluate(labels, mac_args, inst_offset);
+        match labels.get(&const_pool_label(value)) {
+          Some(&n) => n,
+          None => error!(
+            self.pos, "Immediate operands are only allowed in instructions",
+          ),
+        }
+      },
+      OpArgVar::Indirect(..) =>
+        error!(self.pos, "Indirect operands are only allowed in instructions"),
+      OpArgVar::Condition(..) => error!(
+        self.pos, "Comparisons are only allowed in if, while and until",
+      ),
+    }
+  }
+
+  // replaces `name' with `value'
+  fn bind(&self, name: &str, value: u16) -> OpArg {
+    let var = match self.var {
+      OpArgVar::Label(ref label) if label == name => OpArgVar::Number(value),
+      OpArgVar::ArithOp(op, ref lhs, ref rhs) => OpArgVar::ArithOp(
+        op,
+        Box::new(lhs.bind(name, value)),
+        Box::new(rhs.bind(name, value)),
+      ),
+      OpArgVar::Builtin(function, ref arg) =>
+        OpArgVar::Builtin(function, Box::new(arg.bind(name, value))),
+      ref var => var.clone(),
+    };
+    OpArg {
+      var: var,
+      pos: self.pos.clone(),
+    }
+  }
+
+  // for values which must be known before layout; None if the value depends
+  // on anything but constants that are already defined
+  pub fn try_evaluate(&self, labels: &HashMap<String, u16>) -> Option<u16> {
+    match self.var {
+      OpArgVar::Number(n) => Some(n),
+      OpArgVar::Label(ref label) => labels.get(label).cloned(),
+      OpArgVar::ArithOp(op, ref lhs, ref rhs) => {
+        let lhs = lhs.try_evaluate(labels)?;
+        let rhs = rhs.try_evaluate(labels)?;
+        match op {
+          ArithOp::Div if rhs == 0 => None,
+          _ => Some(op.op(lhs, rhs)),
+        }
+      },
+      OpArgVar::Builtin(function, ref arg) =>
+        Some(function.apply(arg.try_evaluate(labels)?)),
+      _ => None,
     }
   }
 }
 
+// the constant pool's words are labelled `#0x1`, `#0xFFFF`, etc.
+pub fn const_pool_label(value: u16) -> String {
+  format!("#0x{:X}", value)
+}
+
 #[derive(Copy, Clone)]
 pub enum ArithOp {
   Add,
-  #[allow(dead_code)]
   Sub,
-  #[allow(dead_code)]
   Mul,
-  #[allow(dead_code)]
   Div,
+  And,
+  Or,
+  Xor,
+  ShiftLeft,
+  ShiftRight,
 }
 
 impl ArithOp {
   pub fn op(self, lhs: u16, rhs: u16) -> u16 {
     match self {
-      ArithOp::Add => lhs + rhs,
-      ArithOp::Sub => lhs - rhs,
-      ArithOp::Mul => lhs * rhs,
+      // arithmetic wraps, like the machine's
+      ArithOp::Add => lhs.wrapping_add(rhs),
+      ArithOp::Sub => lhs.wrapping_sub(rhs),
+      ArithOp::Mul => lhs.wrapping_mul(rhs),
       ArithOp::Div => lhs / rhs,
+      ArithOp::And => lhs & rhs,
+      ArithOp::Or => lhs | rhs,
+      ArithOp::Xor => lhs ^ rhs,
+      // shifting everything out gives 0, rather than wrapping the amount
+      ArithOp::ShiftLeft => lhs.checked_shl(rhs as u32).unwrap_or(0),
+      ArithOp::ShiftRight => lhs.checked_shr(rhs as u32).unwrap_or(0),
     }
   }
 }
 
+#[derive(Copy, Clone)]
+pub enum Builtin {
+  Sin,
+  Cos,
+}
+
+impl Builtin {
+  // angles are in 1/0x10000ths of a turn, and results are signed, scaled to
+  // 0x7FFF; `sin(0x4000)` is 0x7FFF
+  pub fn apply(self, arg: u16) -> u16 {
+    let angle = arg as f64 / 65536.0 * 2.0 * ::std::f64::consts::PI;
+    let value = match self {
+      Builtin::Sin => angle.sin(),
+      Builtin::Cos => angle.cos(),
+    };
+    (value * 32767.0).round() as i16 as u16
+  }
+}
+
 #[derive(Clone)]
 pub enum OpArgVar {
   Number(u16),
   Label(String),
   MacroArg(u16),
   ArithOp(ArithOp, Box<OpArg>, Box<OpArg>),
+  // sin(arg), etc.
+  Builtin(Builtin, Box<OpArg>),
   Here, // $
+  // #value; the address of a constant pool word holding value
+  Immediate(Box<OpArg>),
+  // [base + offset]; the word at mem[base] + offset
+  Indirect(Box<OpArg>, Box<OpArg>),
+  // lhs == rhs, etc.; the conditions of if, while and until
+  Condition(Comparison, Box<OpArg>, Box<OpArg>),
+}
+
+#[derive(Copy, Clone)]
+pub enum Checksum {
+  // the fletcher-style sum of football/cksum.asm
+  Cksum,
+  Sum,
+  Xor,
+  // crc-16/ccitt-false, taking each word high bit first
+  Crc16,
+}
+
+impl Checksum {
+  pub fn compute(self, words: &[u16]) -> u16 {
+    match self {
+      Checksum::Cksum => {
+        let (mut cks1, mut cks2) = (0u16, 0u16);
+        for &dp in words {
+          cks1 = cks1.wrapping_add(dp);
+          cks2 = cks2.wrapping_add(cks1).wrapping_add((dp & 0xFF00) >> 8);
+        }
+        ((cks1 & 0xFF) << 8) | (cks2 & 0xFF)
+      },
+      Checksum::Sum => words.iter().fold(0, |acc, &w| acc.wrapping_add(w)),
+      Checksum::Xor => words.iter().fold(0, |acc, &w| acc ^ w),
+      Checksum::Crc16 => words.iter().fold(0xFFFF, |mut crc, &w| {
+        crc ^= w;
+        for _ in 0..16 {
+          crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+      }),
+    }
+  }
+}
+
+#[derive(Copy, Clone)]
+pub enum Diagnostic {
+  Error,
+  Warning,
+}
+
+#[derive(Copy, Clone)]
+pub enum Comparison {
+  Equal,
+  NotEqual,
+  Lesser,
+  Greater,
+  LesserEqual,
+  GreaterEqual,
 }
 
 #[derive(Copy, Clone)]
@@ -186,6 +346,7 @@ pub enum Public {
 
 #[derive(Clone)]
 pub enum DirectiveVar {
+  #[allow(dead_code)]
   Public(String), // TODO(ubsan): useless for now
   Label(String, Public),
   Import(Vec<String>, Public),
@@ -193,6 +354,25 @@ pub enum DirectiveVar {
   Op(String, Vec<OpArg>),
   // TODO(ubsan): allow non-constant reps?
   Data(Vec<OpArg>),
+  // zeroed words; not emitted when nothing initialized follows them
+  Reserve(u16),
+  // `res N'; becomes a Reserve once N is evaluated
+  ReserveWords(OpArg),
+  // `data rand N'; drawn from the generator set up by the last seed
+  Random(u16),
+  Seed(OpArg),
+  // (name, [(field, size)])
+  Struct(String, Vec<(String, OpArg)>),
+  // (struct name, [(field, initializer, position)])
+  StructData(String, Vec<(String, Vec<OpArg>, Position)>),
+  // (name, kind, [(member, explicit value, position)])
+  Enum(String, EnumKind, Vec<(String, Option<OpArg>, Position)>),
+  // checked once the labels are final
+  Assert(OpArg, Option<String>),
+  // `error "..."' and `warning "..."'
+  Diagnostic(Diagnostic, String),
+  // (name, start, end, algorithm); a word filled in after encoding
+  Checksum(String, OpArg, OpArg, Checksum),
   #[allow(dead_code)]
   Macro {
     name: String,
@@ -201,6 +381,14 @@ pub enum DirectiveVar {
   },
 }
 
+#[derive(Clone)]
+pub enum EnumKind {
+  // (start, step)
+  Sequential(OpArg, OpArg),
+  // powers of two, starting from the given value
+  Flags(OpArg),
+}
+
 #[derive(Clone)]
 pub struct Directive {
   pub var: DirectiveVar,
@@ -210,19 +398,50 @@ pub struct Directive {
 enum TokenVar {
   Ident(Vec<u8>),
   Label(Vec<u8>),
-  StrLit(Vec<u8>),
+  // one word per character
+  StrLit(Vec<u16>),
   NumLit(u16),
+  #[allow(dead_code)]
   MacroArg(u16),
+  #[allow(dead_code)]
   MacroLabel(Vec<u8>),
   Data,
   Equ,
   Rep,
+  Res,
   Macro,
   EndMacro,
   Import,
   Public,
   Here, // $
+  Struct,
+  EndStruct,
+  Enum,
+  EndEnum,
+  Flags,
+  EndFlags,
+  Assert,
+  Error,
+  Warning,
+  Checksum,
   Dot,
+  Minus,
+  Plus,
+  Star,
+  Slash,
+  Ampersand,
+  Pipe,
+  Caret,
+  ShiftLeft,
+  ShiftRight,
+  OpenParen,
+  CloseParen,
+  OpenBrace,
+  CloseBrace,
+  OpenBracket,
+  CloseBracket,
+  Comparison(Comparison),
+  Immediate, // #
   Comma,
   Newline,
 }
@@ -235,12 +454,40 @@ impl Display for TokenVar {
       TokenVar::Newline => write!(f, "newline"),
       TokenVar::Comma => write!(f, "comma"),
       TokenVar::Dot => write!(f, "period"),
+      TokenVar::Minus => write!(f, "minus sign"),
+      TokenVar::Plus => write!(f, "plus sign"),
+      TokenVar::Star => write!(f, "asterisk"),
+      TokenVar::Slash => write!(f, "slash"),
+      TokenVar::Ampersand => write!(f, "ampersand"),
+      TokenVar::Pipe => write!(f, "vertical bar"),
+      TokenVar::Caret => write!(f, "caret"),
+      TokenVar::ShiftLeft => write!(f, "`<<'"),
+      TokenVar::ShiftRight => write!(f, "`>>'"),
+      TokenVar::OpenParen => write!(f, "opening parenthesis"),
+      TokenVar::CloseParen => write!(f, "closing parenthesis"),
+      TokenVar::OpenBrace => write!(f, "opening brace"),
+      TokenVar::CloseBrace => write!(f, "closing brace"),
+      TokenVar::OpenBracket => write!(f, "opening bracket"),
+      TokenVar::CloseBracket => write!(f, "closing bracket"),
+      TokenVar::Comparison(_) => write!(f, "comparison"),
+      TokenVar::Struct => write!(f, "struct directive"),
+      TokenVar::EndStruct => write!(f, "endstruct directive"),
+      TokenVar::Enum => write!(f, "enum directive"),
+      TokenVar::EndEnum => write!(f, "endenum directive"),
+      TokenVar::Flags => write!(f, "flags directive"),
+      TokenVar::EndFlags => write!(f, "endflags directive"),
+      TokenVar::Assert => write!(f, "assert directive"),
+      TokenVar::Error => write!(f, "error directive"),
+      TokenVar::Warning => write!(f, "warning directive"),
+      TokenVar::Checksum => write!(f, "checksum directive"),
       TokenVar::Here => write!(f, "$"),
+      TokenVar::Immediate => write!(f, "#"),
       TokenVar::Equ => write!(f, "equ directive"),
       TokenVar::Import => write!(f, "import directive"),
       TokenVar::Public => write!(f, "public directive"),
       TokenVar::Data => write!(f, "data directive"),
       TokenVar::Rep => write!(f, "rep directive"),
+      TokenVar::Res => write!(f, "res directive"),
       TokenVar::Macro => write!(f, "macro directive"),
       TokenVar::EndMacro => write!(f, "endmacro directive"),
       TokenVar::StrLit(_) => write!(f, "string literal"),
@@ -251,6 +498,32 @@ impl Display for TokenVar {
   }
 }
 
+impl TokenVar {
+  fn keyword(&self) -> Option<&'static str> {
+    match *self {
+      TokenVar::Data => Some("data"),
+      TokenVar::Equ => Some("equ"),
+      TokenVar::Rep => Some("rep"),
+      TokenVar::Res => Some("res"),
+      TokenVar::Macro => Some("macro"),
+      TokenVar::EndMacro => Some("endmacro"),
+      TokenVar::Import => Some("import"),
+      TokenVar::Public => Some("public"),
+      TokenVar::Struct => Some("struct"),
+      TokenVar::EndStruct => Some("endstruct"),
+      TokenVar::Enum => Some("enum"),
+      TokenVar::Flags => Some("flags"),
+      TokenVar::EndEnum => Some("endenum"),
+      TokenVar::EndFlags => Some("endflags"),
+      TokenVar::Assert => Some("assert"),
+      TokenVar::Error => Some("error"),
+      TokenVar::Warning => Some("warning"),
+      TokenVar::Checksum => Some("checksum"),
+      _ => None,
+    }
+  }
+}
+
 struct Token {
   var: TokenVar,
   pos: Position,
@@ -262,6 +535,14 @@ pub struct Lexer {
 
   files: Files,
   pos: Position,
+  peeked: Option<Token>,
+  // whether the last token was at the start of a line
+  line_start: bool,
+  // whether a `#` is an immediate rather than a comment
+  immediate_ok: bool,
+  // the ops which never take operands, so a `#' after them is a comment;
+  // the parser knows which they are. see set_no_operands
+  no_operands: Rc<Vec<Vec<u8>>>,
 }
 // UTILITY
 impl Lexer {
@@ -294,6 +575,26 @@ impl Lexer {
       idx: 0,
       files: files,
       pos: pos,
+      peeked: None,
+      line_start: true,
+      immediate_ok: false,
+      no_operands: Rc::new(Vec::new()),
+    }
+  }
+
+  // for source that isn't in a file; `name' is what positions refer to
+  pub fn from_source(name: &str, source: &str) -> Self {
+    let files = Files::new();
+    let pos = Position::new(name, files.clone());
+    Lexer {
+      input: source.as_bytes().to_owned(),
+      idx: 0,
+      files: files,
+      pos: pos,
+      peeked: None,
+      line_start: true,
+      immediate_ok: false,
+      no_operands: Rc::new(Vec::new()),
     }
   }
 
@@ -306,9 +607,34 @@ impl Lexer {
       idx: 0,
       files: files,
       pos: pos,
+      peeked: None,
+      line_start: true,
+      immediate_ok: false,
+      no_operands: self.no_operands.clone(),
     }
   }
 
+  // for source that's built into the assembler
+  pub fn new_source_lexer(&self, name: &str, source: &str) -> Self {
+    let files = self.files.clone();
+    let pos = Position::new(name, files.clone());
+    Lexer {
+      input: source.as_bytes().to_owned(),
+      idx: 0,
+      files: files,
+      pos: pos,
+      peeked: None,
+      line_start: true,
+      immediate_ok: false,
+      no_operands: self.no_operands.clone(),
+    }
+  }
+
+  // shared with the lexers made from this one, for imports and the runtime
+  pub fn set_no_operands(&mut self, ops: Vec<Vec<u8>>) {
+    self.no_operands = Rc::new(ops);
+  }
+
   pub fn compiler_defined_pos(&self) -> Position {
     Position {
       line: 0,
@@ -325,6 +651,110 @@ impl Lexer {
 
 // TOKENIZATION
 impl Lexer {
+  // the backslash has already been eaten
+  fn escape(&mut self, buff: &mut Vec<u16>) {
+    fn hex_digit(ch: u8) -> Option<u16> {
+      match ch {
+        b'0'..=b'9' => Some((ch - b'0') as u16),
+        b'a'..=b'f' => Some((ch - b'a' + 10) as u16),
+        b'A'..=b'F' => Some((ch - b'A' + 10) as u16),
+        _ => None,
+      }
+    }
+    match self.get_char() {
+      Some((b'\'', _)) => buff.push(b'\'' as u16),
+      Some((b'"', _)) => buff.push(b'"' as u16),
+      Some((b'\\', _)) => buff.push(b'\\' as u16),
+      Some((b'n', _)) => buff.push(b'\n' as u16),
+      Some((b't', _)) => buff.push(b'\t' as u16),
+      Some((b'r', _)) => buff.push(b'\r' as u16),
+      Some((b'0', _)) => buff.push(0),
+      Some((b'\n', _)) => {
+        while let Some(c) = self.peek_char() {
+          if c == b' ' || c == b'\t' || c == b'\r' {
+            self.get_char();
+          } else {
+            return;
+          }
+        }
+        error!(self.pos, "Unexpected EOF")
+      },
+      Some((b'x', pos)) => {
+        let mut n = 0;
+        for _ in 0..2 {
+          match self.get_char() {
+            Some((ch, _)) if hex_digit(ch).is_some() =>
+              n = n * 16 + hex_digit(ch).unwrap(),
+            Some((ch, pos)) => error!(
+              pos, "Expected a hex digit in \\x escape, found `{}'", ch as char,
+            ),
+            None => error!(pos, "Unexpected EOF"),
+          }
+        }
+        buff.push(n);
+      },
+      Some((b'u', pos)) => {
+        match self.get_char() {
+          Some((b'{', _)) => {},
+          _ => error!(pos, "Expected `{{' after \\u"),
+        }
+        let mut n: u32 = 0;
+        let mut digits = 0;
+        loop {
+          match self.get_char() {
+            Some((b'}', _)) if digits > 0 => break,
+            Some((ch, _)) if hex_digit(ch).is_some() && digits < 6 => {
+              n = n * 16 + hex_digit(ch).unwrap() as u32;
+              digits += 1;
+            },
+            Some((ch, pos)) => error!(
+              pos, "Unexpected `{}' in \\u{{...}} escape", ch as char,
+            ),
+            None => error!(pos, "Unexpected EOF"),
+          }
+        }
+        if n > 0xFFFF {
+          error!(
+            pos, "Character U+{:X} does not fit in a 16-bit word", n,
+          );
+        }
+        buff.push(n as u16);
+      },
+      Some((ch, pos)) => error!(
+        pos, "Unrecognized escape sequence: \\{}", ch as char,
+      ),
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+  }
+
+  // non-ascii characters are decoded from utf8, and take up a single word
+  fn utf8_char(&mut self, first: u8, pos: &Position) -> u16 {
+    let len = if first & 0xE0 == 0xC0 {
+      2
+    } else if first & 0xF0 == 0xE0 {
+      3
+    } else if first & 0xF8 == 0xF0 {
+      4
+    } else {
+      error!(pos, "Invalid utf8 in string literal");
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+      match self.get_char() {
+        Some((ch, _)) => bytes.push(ch),
+        None => error!(self.pos, "Unexpected EOF"),
+      }
+    }
+    let c = match ::std::str::from_utf8(&bytes) {
+      Ok(s) => s.chars().next().unwrap() as u32,
+      Err(_) => error!(pos, "Invalid utf8 in string literal"),
+    };
+    if c > 0xFFFF {
+      error!(pos, "Character U+{:X} does not fit in a 16-bit word", c);
+    }
+    c as u16
+  }
+
   fn peek_char(&self) -> Option<u8> {
     self.input.get(self.idx).cloned()
   }
@@ -362,15 +792,15 @@ impl Lexer {
     error!(self.pos, "Unexpected EOF");
   }
 
-  fn next_token(&mut self) -> Option<Token> {
+  fn raw_token(&mut self) -> Option<Token> {
     fn is_space(c: u8) -> bool {
       c == b' ' || c == b'\t' || c == 0x0b || c == 0x0c  || c == b'\r'
     }
     fn is_uppercase(c: u8) -> bool {
-      (c >= b'A' && c <= b'Z')
+      c.is_ascii_uppercase()
     }
     fn is_lowercase(c: u8) -> bool {
-      (c >= b'a' && c <= b'z')
+      c.is_ascii_lowercase()
     }
     fn is_alpha(c: u8) -> bool {
        is_uppercase(c) || is_lowercase(c)
@@ -379,17 +809,17 @@ impl Lexer {
       is_alpha(c) || c == b'_'
     }
     fn is_num(c: u8) -> bool {
-      c >= b'0' && c <= b'9'
+      c.is_ascii_digit()
     }
     fn is_ident(c: u8) -> bool {
       is_ident_start(c) || is_num(c)
     }
     fn is_allowed(c: u8, base: u16) -> bool {
       match base {
-        2 => c >= b'0' && c < b'2',
-        8 => c >= b'0' && c < b'8',
+        2 => (b'0'..b'2').contains(&c),
+        8 => (b'0'..b'8').contains(&c),
         10 => is_num(c),
-        16 => is_num(c) || (c >= b'A' && c <= b'F') || (c >= b'a' && c <= b'f'),
+        16 => is_num(c) || (b'A'..=b'F').contains(&c) || (b'a'..=b'f').contains(&c),
         _ => unreachable!(),
       }
     }
@@ -399,33 +829,40 @@ impl Lexer {
         b'\\' => {
           let ch = self.get_char();
           if let Some((b'\n', _)) = ch {
-            self.next_token()
+            self.raw_token()
           } else if let Some((ch, pos)) = ch {
             error!(pos, "Unexpected `{}' ({})", ch as char, ch)
           } else {
             error!(self.pos, "Unexpected EOF")
           }
         },
+        // `#` directly after the opcode or a comma starts an immediate
+        b'#' if self.immediate_ok && self.peek_char().is_some_and(
+          |c| !is_space(c) && c != b'\n' && c != b'#' && c != b';',
+        ) => Some(Token {
+          var: TokenVar::Immediate,
+          pos: pos,
+        }),
         ch if ch == b'#' || ch == b';' => {
           if let Some(c)  = self.peek_char() {
             if ch == b'#' && c == b'-' {
               self.get_char();
               self.block_comment();
-              return self.next_token();
+              return self.raw_token();
             }
           }
           while let Some(c) = self.peek_char() {
             if c != b'\n' { self.get_char(); }
             else { break; }
           }
-          self.next_token()
+          self.raw_token()
         },
         ch if is_space(ch) => {
           while let Some(c) = self.peek_char() {
             if is_space(c) { self.get_char(); }
             else { break; }
           }
-          self.next_token()
+          self.raw_token()
         },
         b'\n' => Some(Token {
           var: TokenVar::Newline,
@@ -441,34 +878,16 @@ impl Lexer {
         }),
         quote if quote == b'\'' || quote == b'"' => {
           let mut buff = Vec::new();
-          while let Some(ch) = self.get_char() {
-            if ch.0 == b'\\' {
-              match self.get_char() {
-                Some((b'\'', _)) => buff.push(b'\''),
-                Some((b'"', _)) => buff.push(b'"'),
-                Some((b'\n', _)) => {
-                  loop {
-                    if let Some(c) = self.peek_char() {
-                      if is_space(c) {
-                        self.get_char();
-                      } else {
-                        break;
-                      }
-                    } else {
-                      error!(self.pos, "Unexpected EOF")
-                    }
-                  }
-                },
-                Some((b'n', _)) => buff.push(b'\n'),
-                Some((ch, pos)) => error!(
-                  pos, "Unrecogized escape sequence: \\{}", ch,
-                ),
-                None => error!(self.pos, "Unexpected EOF"),
-              }
-            } else if ch.0 == quote {
-              break;
-            } else {
-              buff.push(ch.0);
+          loop {
+            match self.get_char() {
+              Some((b'\\', _)) => self.escape(&mut buff),
+              Some((ch, _)) if ch == quote => break,
+              Some((ch, pos)) if ch >= 0x80 => {
+                let c = self.utf8_char(ch, &pos);
+                buff.push(c);
+              },
+              Some((ch, _)) => buff.push(ch as u16),
+              None => error!(self.pos, "Unexpected EOF"),
             }
           }
           Some(Token {
@@ -501,6 +920,8 @@ impl Lexer {
                 TokenVar::Equ
               } else if ret == b"rep" {
                 TokenVar::Rep
+              } else if ret == b"res" {
+                TokenVar::Res
               } else if ret == b"macro" {
                 TokenVar::Macro
               } else if ret == b"endmacro" {
@@ -509,6 +930,26 @@ impl Lexer {
                 TokenVar::Import
               } else if ret == b"public" {
                 TokenVar::Public
+              } else if ret == b"struct" {
+                TokenVar::Struct
+              } else if ret == b"endstruct" {
+                TokenVar::EndStruct
+              } else if ret == b"enum" {
+                TokenVar::Enum
+              } else if ret == b"endenum" {
+                TokenVar::EndEnum
+              } else if ret == b"flags" {
+                TokenVar::Flags
+              } else if ret == b"endflags" {
+                TokenVar::EndFlags
+              } else if ret == b"assert" {
+                TokenVar::Assert
+              } else if ret == b"error" {
+                TokenVar::Error
+              } else if ret == b"warning" {
+                TokenVar::Warning
+              } else if ret == b"checksum" {
+                TokenVar::Checksum
               } else {
                 TokenVar::Ident(ret)
               }
@@ -526,6 +967,7 @@ impl Lexer {
               Some(b'd') => base = 10,
               Some(b'x') => base = 16,
               Some(ch) if is_num(ch) => ret.push(ch),
+              Some(b'_') => ret.push(b'0'),
               Some(ch) if is_alpha(ch) =>
                 error!(self.pos, "Unknown base specifier: {}", ch as char),
               Some(_) | None => return Some(Token {
@@ -542,6 +984,9 @@ impl Lexer {
             if is_allowed(ch, base) {
               self.get_char();
               ret.push(ch);
+            } else if ch == b'_' {
+              // digit separator
+              self.get_char();
             } else if is_alpha(ch) {
               error!(
                 self.pos,
@@ -553,6 +998,9 @@ impl Lexer {
               break;
             }
           }
+          if ret.is_empty() {
+            error!(pos, "Expected digits after the base specifier");
+          }
           let ret = ret.iter().fold(0, |acc: u16, &el: &u8| {
             let add = if is_uppercase(el) {
               el - b'A' + 10
@@ -565,8 +1013,9 @@ impl Lexer {
               Some(a) => a,
               None => error!(
                 pos,
-                "Attempted to write an overflowing number literal: {}",
+                "Number literal does not fit in 16 bits: {} (base {})",
                 ::std::str::from_utf8(&ret).unwrap(),
+                base,
               ),
             }
           });
@@ -576,7 +1025,7 @@ impl Lexer {
           })
         },
         b'%' => {
-          if let Some(next_tok) = self.next_token() {
+          if let Some(next_tok) = self.raw_token() {
             if let TokenVar::NumLit(n) = next_tok.var {
               Some(Token {
                 var: TokenVar::MacroArg(n),
@@ -597,6 +1046,43 @@ impl Lexer {
           }
         }
         b'.' => Some(Token { var: TokenVar::Dot, pos: pos }),
+        b'-' => Some(Token { var: TokenVar::Minus, pos: pos }),
+        b'+' => Some(Token { var: TokenVar::Plus, pos: pos }),
+        b'*' => Some(Token { var: TokenVar::Star, pos: pos }),
+        b'/' => Some(Token { var: TokenVar::Slash, pos: pos }),
+        b'&' => Some(Token { var: TokenVar::Ampersand, pos: pos }),
+        b'|' => Some(Token { var: TokenVar::Pipe, pos: pos }),
+        b'^' => Some(Token { var: TokenVar::Caret, pos: pos }),
+        b'<' if self.peek_char() == Some(b'<') => {
+          self.get_char();
+          Some(Token { var: TokenVar::ShiftLeft, pos: pos })
+        },
+        b'>' if self.peek_char() == Some(b'>') => {
+          self.get_char();
+          Some(Token { var: TokenVar::ShiftRight, pos: pos })
+        },
+        b'(' => Some(Token { var: TokenVar::OpenParen, pos: pos }),
+        b')' => Some(Token { var: TokenVar::CloseParen, pos: pos }),
+        b'{' => Some(Token { var: TokenVar::OpenBrace, pos: pos }),
+        b'}' => Some(Token { var: TokenVar::CloseBrace, pos: pos }),
+        b'[' => Some(Token { var: TokenVar::OpenBracket, pos: pos }),
+        b'=' | b'!' | b'<' | b'>' => {
+          let equals = self.peek_char() == Some(b'=');
+          if equals {
+            self.get_char();
+          }
+          let cmp = match (ch, equals) {
+            (b'=', true) => Comparison::Equal,
+            (b'!', true) => Comparison::NotEqual,
+            (b'<', false) => Comparison::Lesser,
+            (b'>', false) => Comparison::Greater,
+            (b'<', true) => Comparison::LesserEqual,
+            (b'>', true) => Comparison::GreaterEqual,
+            (ch, _) => error!(pos, "Expected `{}='", ch as char),
+          };
+          Some(Token { var: TokenVar::Comparison(cmp), pos: pos })
+        },
+        b']' => Some(Token { var: TokenVar::CloseBracket, pos: pos }),
         ch => error!(
           pos, "Unsupported character: `{}' (0x{:X})", ch as char, ch,
         ),
@@ -605,6 +1091,45 @@ impl Lexer {
       None
     }
   }
+
+  fn lex_token(&mut self) -> Option<Token> {
+    let tok = self.raw_token();
+    match tok {
+      Some(Token { var: TokenVar::Newline, .. })
+      | Some(Token { var: TokenVar::Label(_), .. }) => {
+        self.line_start = true;
+        self.immediate_ok = false;
+      },
+      Some(Token { var: TokenVar::Ident(ref name), .. }) => {
+        self.immediate_ok = self.line_start && !self.no_operands.contains(name);
+        self.line_start = false;
+      },
+      Some(Token { var: TokenVar::Comma, .. })
+      | Some(Token { var: TokenVar::Comparison(_), .. }) => {
+        self.immediate_ok = true;
+        self.line_start = false;
+      },
+      _ => {
+        self.immediate_ok = false;
+        self.line_start = false;
+      },
+    }
+    tok
+  }
+
+  fn next_token(&mut self) -> Option<Token> {
+    match self.peeked.take() {
+      Some(tok) => Some(tok),
+      None => self.lex_token(),
+    }
+  }
+
+  fn peek_token(&mut self) -> Option<&TokenVar> {
+    if self.peeked.is_none() {
+      self.peeked = self.lex_token();
+    }
+    self.peeked.as_ref().map(|tok| &tok.var)
+  }
 }
 
 // LEXING
@@ -616,28 +1141,218 @@ impl Lexer {
     }
   }
 
+  // negative literals are two's complement, and must fit in an i16
+  fn negative_literal(&mut self, minus_pos: Position) -> OpArg {
+    match self.next_token() {
+      Some(Token { var: TokenVar::NumLit(n), pos }) => {
+        if n > 0x8000 {
+          error!(
+            pos, "Negative number literal does not fit in 16 bits: -{}", n,
+          );
+        }
+        OpArg {
+          var: OpArgVar::Number(n.wrapping_neg()),
+          pos: minus_pos,
+        }
+      },
+      Some(tok) => error!(tok.pos, "Expected a number after `-'"),
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+  }
+
   // None means EOL
-  fn get_op_arg(tok: Token) -> Option<OpArg> {
+  fn get_op_arg(&mut self, tok: Token) -> Option<OpArg> {
+    let lhs = match tok.var {
+      TokenVar::Newline => return None,
+      _ => self.operand(tok),
+    };
+    if let Some(&TokenVar::Comparison(cmp)) = self.peek_token() {
+      self.next_token();
+      let rhs = match self.next_token() {
+        Some(Token { var: TokenVar::Newline, .. }) =>
+          error!(self.pos, "Unexpected newline"),
+        Some(tok) => self.operand(tok),
+        None => error!(self.pos, "Unexpected EOF"),
+      };
+      let pos = lhs.pos.clone();
+      return Some(OpArg {
+        var: OpArgVar::Condition(cmp, Box::new(lhs), Box::new(rhs)),
+        pos: pos,
+      });
+    }
+    Some(lhs)
+  }
+
+  fn operand(&mut self, tok: Token) -> OpArg {
     match tok.var {
-      TokenVar::Newline => None,
-      TokenVar::Here => Some(OpArg {
-        var: OpArgVar::Here,
+      TokenVar::Immediate => {
+        let value = match self.next_token() {
+          Some(Token { var: TokenVar::Newline, .. }) =>
+            error!(self.pos, "Unexpected newline"),
+          Some(tok) => self.operand(tok),
+          None => error!(self.pos, "Unexpected EOF"),
+        };
+        return OpArg {
+          var: OpArgVar::Immediate(Box::new(value)),
+          pos: tok.pos,
+        };
+      },
+      TokenVar::OpenBracket => return self.indirect(tok.pos),
+      _ => {},
+    }
+    self.binary(tok, 0)
+  }
+
+  // binary operators bind like C's: `|', then `^', `&', shifts, `+' and
+  // `-', and tightest, `*' and `/'
+  fn binary(&mut self, tok: Token, level: usize) -> OpArg {
+    fn binary_op(level: usize, tok: &TokenVar) -> Option<ArithOp> {
+      match (level, tok) {
+        (0, &TokenVar::Pipe) => Some(ArithOp::Or),
+        (1, &TokenVar::Caret) => Some(ArithOp::Xor),
+        (2, &TokenVar::Ampersand) => Some(ArithOp::And),
+        (3, &TokenVar::ShiftLeft) => Some(ArithOp::ShiftLeft),
+        (3, &TokenVar::ShiftRight) => Some(ArithOp::ShiftRight),
+        (4, &TokenVar::Plus) => Some(ArithOp::Add),
+        (4, &TokenVar::Minus) => Some(ArithOp::Sub),
+        (5, &TokenVar::Star) => Some(ArithOp::Mul),
+        (5, &TokenVar::Slash) => Some(ArithOp::Div),
+        _ => None,
+      }
+    }
+
+    if level > TERM_LEVEL {
+      return self.unary(tok);
+    }
+    let mut lhs = self.binary(tok, level + 1);
+    while let Some(op) = self.peek_token().and_then(|t| binary_op(level, t)) {
+      self.next_token();
+      let rhs = match self.next_token() {
+        Some(tok) => self.binary(tok, level + 1),
+        None => error!(self.pos, "Unexpected EOF"),
+      };
+      lhs = Self::arith_op(op, lhs, rhs);
+    }
+    lhs
+  }
+
+  // `[bp]`, `[bp - 2]`, `[ptr + Struct.field]`
+  fn indirect(&mut self, pos: Position) -> OpArg {
+    let base = match self.next_token() {
+      Some(tok) => self.atom(tok),
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    let mut offset = OpArg {
+      var: OpArgVar::Number(0),
+      pos: base.pos.clone(),
+    };
+    loop {
+      let op = match self.next_token() {
+        Some(Token { var: TokenVar::CloseBracket, .. }) => break,
+        Some(Token { var: TokenVar::Plus, .. }) => ArithOp::Add,
+        Some(Token { var: TokenVar::Minus, .. }) => ArithOp::Sub,
+        Some(tok) => error!(tok.pos, "Expected a closing bracket"),
+        None => error!(self.pos, "Unexpected EOF"),
+      };
+      let rhs = match self.next_token() {
+        Some(tok) => self.term(tok),
+        None => error!(self.pos, "Unexpected EOF"),
+      };
+      offset = Self::arith_op(op, offset, rhs);
+    }
+    OpArg {
+      var: OpArgVar::Indirect(Box::new(base), Box::new(offset)),
+      pos: pos,
+    }
+  }
+
+  fn arith_op(op: ArithOp, lhs: OpArg, rhs: OpArg) -> OpArg {
+    let pos = lhs.pos.clone();
+    OpArg {
+      var: OpArgVar::ArithOp(op, Box::new(lhs), Box::new(rhs)),
+      pos: pos,
+    }
+  }
+
+  fn term(&mut self, tok: Token) -> OpArg {
+    self.binary(tok, TERM_LEVEL)
+  }
+
+  fn unary(&mut self, tok: Token) -> OpArg {
+    if let TokenVar::Minus = tok.var {
+      if let Some(&TokenVar::NumLit(_)) = self.peek_token() {
+        return self.negative_literal(tok.pos);
+      }
+      let arg = match self.next_token() {
+        Some(next) => self.unary(next),
+        None => error!(self.pos, "Unexpected EOF"),
+      };
+      let zero = OpArg {
+        var: OpArgVar::Number(0),
         pos: tok.pos,
-      }),
-      TokenVar::Ident(id) => Some(OpArg {
-        var: OpArgVar::Label(Self::to_string(&tok.pos, id)),
+      };
+      Self::arith_op(ArithOp::Sub, zero, arg)
+    } else {
+      self.atom(tok)
+    }
+  }
+
+  fn atom(&mut self, tok: Token) -> OpArg {
+    match tok.var {
+      TokenVar::Here => OpArg {
+        var: OpArgVar::Here,
         pos: tok.pos,
-      }),
-      TokenVar::NumLit(n) => Some(OpArg {
+      },
+      TokenVar::Ident(id) => {
+        // `sin(x)`
+        if let Some(&TokenVar::OpenParen) = self.peek_token() {
+          let function = match &*id {
+            b"sin" => Builtin::Sin,
+            b"cos" => Builtin::Cos,
+            _ => error!(
+              tok.pos, "Unknown function: {}", String::from_utf8_lossy(&id),
+            ),
+          };
+          let paren = self.next_token().unwrap();
+          let arg = self.atom(paren);
+          return OpArg {
+            var: OpArgVar::Builtin(function, Box::new(arg)),
+            pos: tok.pos,
+          };
+        }
+        // `Struct.field`
+        let mut name = Self::to_string(&tok.pos, id);
+        while let Some(&TokenVar::Dot) = self.peek_token() {
+          self.next_token();
+          match self.next_token() {
+            Some(Token { var: TokenVar::Ident(id), pos }) => {
+              name.push('.');
+              name.push_str(&Self::to_string(&pos, id));
+            },
+            // field names may be keywords, like `Password.data`
+            Some(ref tok) if tok.var.keyword().is_some() => {
+              name.push('.');
+              name.push_str(tok.var.keyword().unwrap());
+            },
+            Some(tok) => error!(tok.pos, "Expected an identifier after `.'"),
+            None => error!(self.pos, "Unexpected EOF"),
+          }
+        }
+        OpArg {
+          var: OpArgVar::Label(name),
+          pos: tok.pos,
+        }
+      },
+      TokenVar::NumLit(n) => OpArg {
         var: OpArgVar::Number(n),
         pos: tok.pos,
-      }),
+      },
       TokenVar::StrLit(s) => {
         if s.len() == 1 {
-          Some(OpArg {
-            var: OpArgVar::Number(s[0] as u16),
+          OpArg {
+            var: OpArgVar::Number(s[0]),
             pos: tok.pos
-          })
+          }
         } else if s.is_empty() {
           error!(
             tok.pos, "Unexpected empty string literal",
@@ -647,7 +1362,22 @@ impl Lexer {
             tok.pos, "Unexpected multi-char string literal",
           );
         }
-      }
+      },
+      TokenVar::OpenParen => {
+        let inner = match self.next_token() {
+          Some(tok) => match self.get_op_arg(tok) {
+            Some(arg) => arg,
+            None => error!(self.pos, "Unexpected newline"),
+          },
+          None => error!(self.pos, "Unexpected EOF"),
+        };
+        match self.next_token() {
+          Some(Token { var: TokenVar::CloseParen, .. }) => {},
+          Some(tok) => error!(tok.pos, "Expected a closing parenthesis"),
+          None => error!(self.pos, "Unexpected EOF"),
+        }
+        inner
+      },
       tv => error!(tok.pos, "Unexpected {}", tv),
     }
   }
@@ -668,7 +1398,7 @@ impl Lexer {
       let pos = tok.pos.clone();
       if let TokenVar::Newline = tok.var {
         break;
-      } else if let Some(arg) = Self::get_op_arg(tok) {
+      } else if let Some(arg) = self.get_op_arg(tok) {
         args.push(arg);
         if let Some(tok) = self.next_token() {
           if let TokenVar::Newline = tok.var {
@@ -690,61 +1420,123 @@ impl Lexer {
     }
   }
 
+  // a single item of a data directive; commas and newlines are up to the
+  // caller
+  fn data_item(&mut self, tok: Token, data: &mut Vec<OpArg>) {
+    match tok.var {
+      TokenVar::Rep => {
+        let repetitions = match self.next_token() {
+          Some(tok) => match tok.var {
+            TokenVar::NumLit(n) => n,
+            _ => error!(
+              tok.pos, "Expected literal number of repetitions",
+            ),
+          },
+          None => error!(tok.pos, "Unexpected EOF"),
+        };
+        match self.next_token() {
+          Some(tok) => match self.get_op_arg(tok) {
+            Some(op) => for _ in 0..repetitions {
+              data.push(op.clone());
+            },
+            None => error!(self.pos, "Unexpected newline"),
+          },
+          None => error!(self.pos, "Unexpected EOF"),
+        }
+      },
+      // for NAME N EXPR; EXPR for each NAME in 0..N. `for' is only special
+      // here, and only before a name
+      TokenVar::Ident(ref word) if word == b"for"
+        && matches!(self.peek_token(), Some(&TokenVar::Ident(_))) =>
+      {
+        let name = match self.next_token() {
+          Some(Token { var: TokenVar::Ident(s), pos }) =>
+            Self::to_string(&pos, s),
+          Some(tok) => error!(tok.pos, "Expected a name for the index"),
+          None => error!(tok.pos, "Unexpected EOF"),
+        };
+        let count = match self.next_token() {
+          Some(Token { var: TokenVar::NumLit(n), .. }) => n,
+          Some(tok) => error!(tok.pos, "Expected literal number of items"),
+          None => error!(tok.pos, "Unexpected EOF"),
+        };
+        let item = match self.next_token() {
+          Some(tok) => match self.get_op_arg(tok) {
+            Some(item) => item,
+            None => error!(self.pos, "Unexpected newline"),
+          },
+          None => error!(self.pos, "Unexpected EOF"),
+        };
+        data.extend((0..count).map(|i| item.bind(&name, i)));
+      },
+      TokenVar::Immediate =>
+        error!(tok.pos, "Immediate operands are only allowed in instructions"),
+      TokenVar::OpenBracket =>
+        error!(tok.pos, "Indirect operands are only allowed in instructions"),
+      TokenVar::StrLit(ref s) if s.len() != 1 => {
+        data.extend(s.iter().map(|&c| OpArg {
+          var: OpArgVar::Number(c),
+          pos: tok.pos.clone(),
+        }));
+      },
+      _ => match self.get_op_arg(tok) {
+        Some(arg) => data.push(arg),
+        None => error!(self.pos, "Unexpected newline"),
+      },
+    }
+  }
+
+  // the message of an assert, error or warning
+  fn message(&mut self) -> String {
+    match self.next_token() {
+      Some(Token { var: TokenVar::StrLit(s), pos }) => {
+        match self.next_token() {
+          Some(Token { var: TokenVar::Newline, .. }) | None => {},
+          Some(tok) => error!(tok.pos, "Expected a newline"),
+        }
+        match String::from_utf16(&s) {
+          Ok(s) => s,
+          Err(_) => error!(pos, "Invalid message"),
+        }
+      },
+      Some(tok) => error!(tok.pos, "Expected a string literal"),
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+  }
+
+  // assert EXPR[, "message"]
+  fn dir_assert(&mut self, pos: Position) -> Directive {
+    let cond = match self.next_token() {
+      Some(tok) => match self.get_op_arg(tok) {
+        Some(arg) => arg,
+        None => error!(pos, "Expected an expression for assert"),
+      },
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    let message = match self.next_token() {
+      Some(Token { var: TokenVar::Newline, .. }) | None => None,
+      Some(Token { var: TokenVar::Comma, .. }) => Some(self.message()),
+      Some(tok) => error!(tok.pos, "Expected a comma or a newline"),
+    };
+    Directive {
+      var: DirectiveVar::Assert(cond, message),
+      pos: pos,
+    }
+  }
+
+  fn dir_diagnostic(&mut self, pos: Position, kind: Diagnostic) -> Directive {
+    let message = self.message();
+    Directive {
+      var: DirectiveVar::Diagnostic(kind, message),
+      pos: pos,
+    }
+  }
+
   fn dir_data(&mut self, pos: Position) -> Directive {
     let mut data = Vec::new();
     let mut must_get = true;
     while let Some(tok) = self.next_token() {
       match tok.var {
-        TokenVar::Rep => {
-          let repetitions = match self.next_token() {
-            Some(tok) => match tok.var {
-              TokenVar::NumLit(n) => n,
-              _ => error!(
-                tok.pos, "Expected literal number of repetitions",
-              ),
-            },
-            None => error!(tok.pos, "Unexpected EOF"),
-          };
-          match self.next_token() {
-            Some(tok) => match Self::get_op_arg(tok) {
-              Some(op) => for _ in 0..repetitions {
-                data.push(op.clone());
-              },
-              None => error!(self.pos, "Unexpected newline"),
-            },
-            None => error!(self.pos, "Unexpected EOF"),
-          }
-          must_get = false;
-        }
-        TokenVar::Ident(id) => {
-          data.push(OpArg {
-            var: OpArgVar::Label(Self::to_string(&tok.pos, id)),
-            pos: tok.pos,
-          });
-          must_get = false;
-        },
-        TokenVar::StrLit(s) => {
-          let pos = tok.pos;
-          data.extend(s.into_iter().map(|c| OpArg {
-            var: OpArgVar::Number(c as u16),
-            pos: pos.clone(),
-          }));
-          must_get = false;
-        },
-        TokenVar::NumLit(n) => {
-          data.push(OpArg {
-            var: OpArgVar::Number(n),
-            pos: tok.pos,
-          });
-          must_get = false;
-        },
-        TokenVar::Here => {
-          data.push(OpArg {
-            var: OpArgVar::Here,
-            pos: tok.pos,
-          });
-          must_get = false;
-        },
         TokenVar::Comma => {
           if must_get {
             error!(tok.pos, "Unexpected comma");
@@ -756,12 +1548,219 @@ impl Lexer {
             error!(tok.pos, "Unexpected newline");
           }
           break
+        },
+        // rand N; like `for', `rand' is only special here, before a number
+        TokenVar::Ident(ref word) if word == b"rand"
+          && matches!(self.peek_token(), Some(&TokenVar::NumLit(_))) =>
+        {
+          if !data.is_empty() {
+            error!(tok.pos, "Random data must be alone in a data directive");
+          }
+          let words = match self.next_token() {
+            Some(Token { var: TokenVar::NumLit(n), .. }) => n,
+            _ => unreachable!(),
+          };
+          match self.next_token() {
+            Some(Token { var: TokenVar::Newline, .. }) | None => {},
+            Some(tok) => error!(tok.pos, "Expected a newline"),
+          }
+          return Directive {
+            var: DirectiveVar::Random(words),
+            pos: pos,
+          };
+        },
+        TokenVar::Ident(name) => {
+          if let Some(&TokenVar::OpenBrace) = self.peek_token() {
+            if !data.is_empty() {
+              error!(tok.pos, "A struct initializer must be alone in a data directive");
+            }
+            let name = Self::to_string(&tok.pos, name);
+            return self.struct_data(pos, name);
+          }
+          let tok = Token {
+            var: TokenVar::Ident(name),
+            pos: tok.pos,
+          };
+          self.data_item(tok, &mut data);
+          must_get = false;
+        },
+        _ => {
+          self.data_item(tok, &mut data);
+          must_get = false;
+        },
+      }
+    }
+    Directive {
+      var: DirectiveVar::Data(data),
+      pos: pos,
+    }
+  }
+
+  // checksum NAME, start, end, algorithm
+  fn dir_checksum(&mut self, pos: Position) -> Directive {
+    let name = match self.next_token() {
+      Some(Token { var: TokenVar::Ident(s), pos }) => Self::to_string(&pos, s),
+      Some(tok) =>
+        error!(tok.pos, "Expected identifier for checksum directive"),
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    let start = self.comma_op_arg();
+    let end = self.comma_op_arg();
+    match self.next_token() {
+      Some(Token { var: TokenVar::Comma, .. }) => {},
+      Some(tok) => error!(tok.pos, "Expected a comma"),
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+    let algorithm = match self.next_token() {
+      Some(Token { var: TokenVar::Ident(s), pos }) => match &*s {
+        b"cksum" => Checksum::Cksum,
+        b"sum" => Checksum::Sum,
+        b"xor" => Checksum::Xor,
+        b"crc16" => Checksum::Crc16,
+        _ => error!(
+          pos,
+          "Unknown checksum: {}; expected cksum, sum, xor or crc16",
+          String::from_utf8_lossy(&s),
+        ),
+      },
+      Some(tok) => error!(tok.pos, "Expected a checksum algorithm"),
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    match self.next_token() {
+      Some(Token { var: TokenVar::Newline, .. }) | None => {},
+      Some(tok) => error!(tok.pos, "Expected a newline"),
+    }
+    Directive {
+      var: DirectiveVar::Checksum(name, start, end, algorithm),
+      pos: pos,
+    }
+  }
+
+  // `, arg'
+  fn comma_op_arg(&mut self) -> OpArg {
+    match self.next_token() {
+      Some(Token { var: TokenVar::Comma, .. }) => {},
+      Some(tok) => error!(tok.pos, "Expected a comma"),
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+    match self.next_token() {
+      Some(tok) => match self.get_op_arg(tok) {
+        Some(arg) => arg,
+        None => error!(self.pos, "Unexpected newline"),
+      },
+      None => error!(self.pos, "Unexpected EOF"),
+    }
+  }
+
+  // seed EXPR
+  fn dir_seed(&mut self, pos: Position) -> Directive {
+    let seed = match self.next_token() {
+      Some(tok) => match self.get_op_arg(tok) {
+        Some(arg) => arg,
+        None => error!(pos, "Expected an expression for seed"),
+      },
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    match self.next_token() {
+      Some(Token { var: TokenVar::Newline, .. }) | None => {},
+      Some(tok) => error!(tok.pos, "Expected a newline"),
+    }
+    Directive {
+      var: DirectiveVar::Seed(seed),
+      pos: pos,
+    }
+  }
+
+  // res N
+  fn dir_reserve(&mut self, pos: Position) -> Directive {
+    let words = match self.next_token() {
+      Some(tok) => {
+        let pos = tok.pos.clone();
+        match self.get_op_arg(tok) {
+          Some(arg) => arg,
+          None => error!(pos, "Expected the number of words"),
         }
+      },
+      None => error!(self.pos, "Unexpected EOF"),
+    };
+    match self.next_token() {
+      Some(Token { var: TokenVar::Newline, .. }) | None => {},
+      Some(tok) => error!(tok.pos, "Expected a newline"),
+    }
+    Directive {
+      var: DirectiveVar::ReserveWords(words),
+      pos: pos,
+    }
+  }
+
+  // data Name { field: items, field: items }
+  fn struct_data(&mut self, pos: Position, name: String) -> Directive {
+    self.next_token(); // the opening brace
+    let mut fields: Vec<(String, Vec<OpArg>, Position)> = Vec::new();
+    loop {
+      let tok = match self.next_token() {
+        Some(tok) => tok,
+        None => error!(pos, "Unterminated struct initializer"),
+      };
+      match tok.var {
+        TokenVar::CloseBrace => break,
+        TokenVar::Newline | TokenVar::Comma => {},
+        TokenVar::Label(field) => {
+          let field = Self::to_string(&tok.pos, field);
+          fields.push((field, Vec::new(), tok.pos));
+        },
+        _ => match fields.last_mut() {
+          Some(&mut (_, ref mut data, _)) => self.data_item(tok, data),
+          None => error!(tok.pos, "Expected a field name"),
+        },
+      }
+    }
+    match self.next_token() {
+      Some(Token { var: TokenVar::Newline, .. }) | None => {},
+      Some(tok) => error!(tok.pos, "Expected a newline"),
+    }
+    Directive {
+      var: DirectiveVar::StructData(name, fields),
+      pos: pos,
+    }
+  }
+
+  fn dir_struct(&mut self, pos: Position) -> Directive {
+    let name = match self.next_token() {
+      Some(Token { var: TokenVar::Ident(s), pos }) => Self::to_string(&pos, s),
+      Some(tok) => error!(tok.pos, "Expected identifier for struct directive"),
+      None => error!(pos, "Unexpected EOF"),
+    };
+    let mut fields = Vec::new();
+    loop {
+      let tok = match self.next_token() {
+        Some(tok) => tok,
+        None => error!(pos, "Unterminated struct: {}", name),
+      };
+      match tok.var {
+        TokenVar::Newline => {},
+        TokenVar::EndStruct => break,
+        // a field with no size is a single word
+        TokenVar::Label(field) => {
+          let field = Self::to_string(&tok.pos, field);
+          let size = match self.next_token() {
+            Some(Token { var: TokenVar::Newline, .. }) => OpArg {
+              var: OpArgVar::Number(1),
+              pos: tok.pos,
+            },
+            Some(size) => match self.get_op_arg(size) {
+              Some(arg) => arg,
+              None => unreachable!(),
+            },
+            None => error!(self.pos, "Unexpected EOF"),
+          };
+          fields.push((field, size));
+        },
         tv => error!(tok.pos, "Unexpected {}", tv),
       }
     }
     Directive {
-      var: DirectiveVar::Data(data),
+      var: DirectiveVar::Struct(name, fields),
       pos: pos,
     }
   }
@@ -807,6 +1806,9 @@ impl Lexer {
       loop {
         match tok.var {
           TokenVar::Ident(s) => parts.push(Self::to_string(&tok.pos, s)),
+          // files may share a name with a keyword, like `import flags`
+          ref tv if tv.keyword().is_some() =>
+            parts.push(tv.keyword().unwrap().to_owned()),
           tv => error!(tok.pos, "Unexpected {}", tv),
         }
         if let Some(tok) = self.next_token() {
@@ -852,7 +1854,7 @@ impl Lexer {
         };
       }
       let pos = tok.pos.clone();
-      match Self::get_op_arg(tok) {
+      match self.get_op_arg(tok) {
         Some(op) => op,
         None => error!(pos, "Unexpected newline"),
       }
@@ -865,16 +1867,111 @@ impl Lexer {
     }
   }
 
+  // enum Name[, start[, step]]
+  // flags Name[, start]
+  fn dir_enum(&mut self, pos: Position, flags: bool) -> Directive {
+    let name = match self.next_token() {
+      Some(Token { var: TokenVar::Ident(s), pos }) => Self::to_string(&pos, s),
+      Some(tok) => error!(tok.pos, "Expected identifier for enum directive"),
+      None => error!(pos, "Unexpected EOF"),
+    };
+    let mut params = Vec::new();
+    loop {
+      match self.next_token() {
+        Some(Token { var: TokenVar::Newline, .. }) => break,
+        Some(Token { var: TokenVar::Comma, .. }) => {
+          match self.next_token() {
+            Some(tok) => match self.get_op_arg(tok) {
+              Some(arg) => params.push(arg),
+              None => error!(self.pos, "Unexpected newline"),
+            },
+            None => error!(self.pos, "Unexpected EOF"),
+          }
+        },
+        Some(tok) => error!(tok.pos, "Expected a comma or a newline"),
+        None => error!(pos, "Unexpected EOF"),
+      }
+    }
+    let max_params = if flags { 1 } else { 2 };
+    if params.len() > max_params {
+      error!(
+        params[max_params].pos,
+        "Too many parameters for {}; expected at most {}",
+        if flags { "flags" } else { "enum" },
+        max_params,
+      );
+    }
+    let mut params = params.into_iter();
+    let mut param = |default: u16| match params.next() {
+      Some(arg) => arg,
+      None => OpArg {
+        var: OpArgVar::Number(default),
+        pos: pos.clone(),
+      },
+    };
+    let kind = if flags {
+      EnumKind::Flags(param(1))
+    } else {
+      let start = param(0);
+      EnumKind::Sequential(start, param(1))
+    };
+
+    let mut members = Vec::new();
+    loop {
+      let tok = match self.next_token() {
+        Some(tok) => tok,
+        None => error!(pos, "Unterminated enum: {}", name),
+      };
+      match tok.var {
+        TokenVar::Newline => {},
+        TokenVar::EndEnum if !flags => break,
+        TokenVar::EndFlags if flags => break,
+        TokenVar::Ident(member) => {
+          let member = Self::to_string(&tok.pos, member);
+          members.push((member, None, tok.pos));
+        },
+        // a member with an explicit value
+        TokenVar::Label(member) => {
+          let member = Self::to_string(&tok.pos, member);
+          let value = match self.next_token() {
+            Some(value) => match self.get_op_arg(value) {
+              Some(arg) => arg,
+              None => error!(tok.pos, "Expected a value for {}", member),
+            },
+            None => error!(self.pos, "Unexpected EOF"),
+          };
+          members.push((member, Some(value), tok.pos));
+        },
+        tv => error!(tok.pos, "Unexpected {}", tv),
+      }
+    }
+    Directive {
+      var: DirectiveVar::Enum(name, kind, members),
+      pos: pos,
+    }
+  }
+
   pub fn next_directive(&mut self) -> Option<Directive> {
     if let Some(tok) = self.next_token() {
       Some(match tok.var {
         TokenVar::Newline => return self.next_directive(),
         TokenVar::Label(label) => self.dir_label(tok.pos, label),
+        // `seed' is only a directive at the start of a line
+        TokenVar::Ident(ref op) if op == b"seed" => self.dir_seed(tok.pos),
         TokenVar::Ident(op) => self.dir_ident(tok.pos, op),
         TokenVar::Data => self.dir_data(tok.pos),
+        TokenVar::Res => self.dir_reserve(tok.pos),
+        TokenVar::Checksum => self.dir_checksum(tok.pos),
         TokenVar::Public => self.dir_public(tok.pos),
         TokenVar::Import => self.dir_import(tok.pos),
         TokenVar::Equ => self.dir_equ(tok.pos),
+        TokenVar::Struct => self.dir_struct(tok.pos),
+        TokenVar::Enum => self.dir_enum(tok.pos, false),
+        TokenVar::Flags => self.dir_enum(tok.pos, true),
+        TokenVar::Assert => self.dir_assert(tok.pos),
+        TokenVar::Error => self.dir_diagnostic(tok.pos, Diagnostic::Error),
+        TokenVar::Warning =>
+          self.dir_diagnostic(tok.pos, Diagnostic::Warning),
         TokenVar::Macro | TokenVar::EndMacro =>
           error!(tok.pos, "Macros are not yet implemented"),
         tv => error!(tok.pos, "Unexpected {}", tv),
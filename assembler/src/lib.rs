@@ -0,0 +1,222 @@
+// `field: field` is the house style; it predates shorthand initialization
+#![allow(clippy::redundant_field_names)]
+
+use std::fmt::{self, Debug};
+use std::path::Path;
+
+#[macro_use]
+extern crate maplit;
+
+#[macro_use]
+pub mod macros;
+pub mod lexer;
+pub mod parser;
+pub mod container;
+pub mod elf;
+pub mod export;
+pub mod emulator;
+pub mod debugger;
+
+pub struct Opcode {
+  var: OpcodeVariant,
+  reg: u16,
+  num: u16,
+}
+
+// what each instruction does, which is what emulator::Machine runs. `reg'
+// is the 12 bit operand, and `num' the 16 bit one; ip has already moved
+// past the instruction when it runs, and arithmetic wraps
+pub enum OpcodeVariant {
+  // mem[reg] = num
+  MoveImmediate,
+  // mem[reg] = mem[num]
+  Move,
+  // mem[reg] = mem[mem[num]]
+  MoveDeref,
+  // mem[mem[reg]] = mem[num]
+  Load,
+  // mem[mem[num]] = mem[reg]
+  Store,
+  // mem[reg] += mem[num]
+  Add,
+  // mem[reg] -= mem[num]
+  Sub,
+  // mem[reg] &= mem[num]
+  And,
+  // mem[reg] |= mem[num]
+  Or,
+  // mem[reg] ^= mem[num]
+  Xor,
+  // mem[reg] >>= mem[num]; shifting by 16 or more gives 0
+  ShiftRight,
+  // mem[reg] <<= mem[num]; likewise
+  ShiftLeft,
+  // the signed mem[reg] >>= mem[num]; by 16 or more gives 0 or -1
+  ShiftArithmetic,
+  // ip = label if mem[reg] > mem[num], unsigned
+  JumpGreater(u16),
+  // ip = label if mem[reg] < mem[num], unsigned
+  JumpLesser(u16),
+  // ip = label if mem[reg] == mem[num]; `jq ip, ip, $', which is hf, halts
+  JumpEqual(u16),
+  Data(Vec<u16>), // does not use reg or num
+}
+
+impl Opcode {
+  // the instruction at `address' in `memory', which is the whole 64k words,
+  // and how many words it takes
+  pub fn decode(memory: &[u16], address: u16) -> (Opcode, u16) {
+    use OpcodeVariant::*;
+    let word = |offset: u16| memory[address.wrapping_add(offset) as usize];
+    let (var, size) = match word(0) >> 12 {
+      0x0 => (MoveImmediate, 2),
+      0x1 => (Move, 2),
+      0x2 => (MoveDeref, 2),
+      0x3 => (Load, 2),
+      0x4 => (Store, 2),
+      0x5 => (Add, 2),
+      0x6 => (Sub, 2),
+      0x7 => (And, 2),
+      0x8 => (Or, 2),
+      0x9 => (Xor, 2),
+      0xA => (ShiftRight, 2),
+      0xB => (ShiftLeft, 2),
+      0xC => (ShiftArithmetic, 2),
+      0xD => (JumpGreater(word(2)), 3),
+      0xE => (JumpLesser(word(2)), 3),
+      _ => (JumpEqual(word(2)), 3),
+    };
+    (Opcode {
+      var: var,
+      reg: word(0) & 0x0FFF,
+      num: word(1),
+    }, size)
+  }
+
+  pub fn encode(&self, out: &mut Vec<u16>) {
+    use OpcodeVariant::*;
+    let opcode = match self.var {
+      MoveImmediate => 0x0,
+      Move => 0x1,
+      MoveDeref => 0x2,
+      Load => 0x3,
+      Store => 0x4,
+      Add => 0x5,
+      Sub => 0x6,
+      And => 0x7,
+      Or => 0x8,
+      Xor => 0x9,
+      ShiftRight => 0xA,
+      ShiftLeft => 0xB,
+      ShiftArithmetic => 0xC,
+      JumpGreater(_) => 0xD,
+      JumpLesser(_) => 0xE,
+      JumpEqual(_) => 0xF,
+      Data(ref nums) => return out.extend_from_slice(nums),
+    };
+    out.extend_from_slice(&[(opcode << 12) | self.reg, self.num]);
+    match self.var {
+      JumpGreater(label) | JumpLesser(label) | JumpEqual(label) => {
+        out.push(label)
+      },
+      _ => {},
+    }
+  }
+
+  pub fn mnemonic(&self) -> &'static str {
+    use OpcodeVariant::*;
+    match self.var {
+      MoveImmediate => "mi",
+      Move => "mv",
+      MoveDeref => "md",
+      Load => "ld",
+      Store => "st",
+      Add => "ad",
+      Sub => "sb",
+      And => "nd",
+      Or => "or",
+      Xor => "xr",
+      ShiftRight => "sr",
+      ShiftLeft => "sl",
+      ShiftArithmetic => "sa",
+      JumpGreater(_) => "jg",
+      JumpLesser(_) => "jl",
+      JumpEqual(_) => "jq",
+      Data(_) => "data",
+    }
+  }
+}
+
+impl Debug for Opcode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use OpcodeVariant::*;
+    match self.var {
+      MoveImmediate => write!(f, "MI {}, {}", self.reg, self.num),
+      Move => write!(f, "MV {}, {}", self.reg, self.num),
+      MoveDeref => write!(f, "MD {}, {}", self.reg, self.num),
+      Load => write!(f, "LD {}, {}", self.reg, self.num),
+      Store => write!(f, "ST {}, {}", self.reg, self.num),
+      Add => write!(f, "AD {}, {}", self.reg, self.num),
+      Sub => write!(f, "SB {}, {}", self.reg, self.num),
+      And => write!(f, "ND {}, {}", self.reg, self.num),
+      Or => write!(f, "OR {}, {}", self.reg, self.num),
+      Xor => write!(f, "XR {}, {}", self.reg, self.num),
+      ShiftRight => write!(f, "SR {}, {}", self.reg, self.num),
+      ShiftLeft => write!(f, "SL {}, {}", self.reg, self.num),
+      ShiftArithmetic => write!(f, "SA {}, {}", self.reg, self.num),
+      JumpGreater(label) =>
+        write!(f, "JG {}, {}, {}", self.reg, self.num, label),
+      JumpLesser(label) =>
+        write!(f, "JL {}, {}, {}", self.reg, self.num, label),
+      JumpEqual(label) =>
+        write!(f, "JQ {}, {}, {}", self.reg, self.num, label),
+      Data(ref nums) => {
+        write!(f, "DATA ")?;
+        for el in nums {
+          write!(f, "{} ", el)?;
+        }
+        write!(f, "ENDDATA")
+      }
+    }
+  }
+}
+
+// decimal, or hexadecimal with 0x
+pub fn parse_number(s: &str) -> Option<u16> {
+  match s.strip_prefix("0x") {
+    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+    None => s.parse().ok(),
+  }
+}
+
+// an assembled program; the image is loaded at parser::INST_OFFSET_BASE
+pub struct Program {
+  pub image: Vec<u16>,
+  pub parser: parser::Parser,
+}
+
+impl Program {
+  pub fn new(filename: &str, options: &parser::Options) -> Self {
+    Program::assemble(parser::Parser::new(filename, options))
+  }
+
+  // see Parser::from_source
+  pub fn from_source(
+    path: &Path, source: &str, options: &parser::Options,
+  ) -> Self {
+    Program::assemble(parser::Parser::from_source(path, source, options))
+  }
+
+  fn assemble(mut parser: parser::Parser) -> Self {
+    let mut image = Vec::new();
+    for op in parser.by_ref() {
+      op.encode(&mut image);
+    }
+    parser.apply_checksums(&mut image);
+    parser.apply_encryption(&mut image);
+    Program {
+      image: image,
+      parser: parser,
+    }
+  }
+}
@@ -1,58 +1,166 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Display};
+use std::panic;
+use std::process;
+
+use lexer::Position;
+
+// errors exit the process, unless they're being caught; see catch_errors
+#[macro_export]
 macro_rules! error {
   ($position:expr, $fmt:expr) => ({
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(concat!("error: ", $fmt))
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(" at {}\n", $position)
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(
-        "note: assembler at {}:{}\n",  file!(), line!(),
-      ),
-    ).expect("Writing to stderr failed");
-    ::std::process::exit(1);
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::fail(
+      format!($fmt),
+      Some(position),
+      format!("note: assembler at {}:{}", file!(), line!()),
+    )
   });
   ($position:expr, $fmt:expr, $($arg:tt)*) => ({
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(concat!("error: ", $fmt), $($arg)*)
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(" at {}\n", $position)
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(
-        "note: assembler at {}:{}\n",  file!(), line!(),
-      ),
-    ).expect("Writing to stderr failed");
-    ::std::process::exit(1);
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::fail(
+      format!($fmt, $($arg)*),
+      Some(position),
+      format!("note: assembler at {}:{}", file!(), line!()),
+    )
   });
 }
 
 // no line number
+#[macro_export]
 macro_rules! error_np {
   ($fmt:expr) => ({
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(concat!("error: ", $fmt, "\n"))
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(
-        "note: error found here: {}:{}\n",  file!(), line!(),
-      ),
-    ).expect("Writing to stderr failed");
-    ::std::process::exit(1);
+    $crate::macros::fail(
+      format!($fmt),
+      None,
+      format!("note: error found here: {}:{}", file!(), line!()),
+    )
   });
   ($fmt:expr, $($arg:tt)*) => ({
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(
-        concat!("error: ", $fmt, "\n"), $($arg)*
-      )
-    ).expect("Writing to stderr failed");
-    <::std::io::Stderr as ::std::io::Write>::write_fmt(
-      &mut ::std::io::stderr(), format_args!(
-        "note: error found here: {}:{}\n",  file!(), line!(),
-      ),
-    ).expect("Writing to stderr failed");
-    ::std::process::exit(1);
+    $crate::macros::fail(
+      format!($fmt, $($arg)*),
+      None,
+      format!("note: error found here: {}:{}", file!(), line!()),
+    )
+  });
+}
+
+// doesn't stop assembly
+macro_rules! note {
+  ($position:expr, $fmt:expr) => ({
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::report(
+      $crate::macros::Level::Note, format!($fmt), position,
+    )
+  });
+  ($position:expr, $fmt:expr, $($arg:tt)*) => ({
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::report(
+      $crate::macros::Level::Note, format!($fmt, $($arg)*), position,
+    )
   });
 }
+
+// doesn't stop assembly
+macro_rules! warning {
+  ($position:expr, $fmt:expr) => ({
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::report(
+      $crate::macros::Level::Warning, format!($fmt), position,
+    )
+  });
+  ($position:expr, $fmt:expr, $($arg:tt)*) => ({
+    let position: &$crate::lexer::Position = &$position;
+    $crate::macros::report(
+      $crate::macros::Level::Warning, format!($fmt, $($arg)*), position,
+    )
+  });
+}
+
+thread_local! {
+  static CATCHING: Cell<bool> = const { Cell::new(false) };
+  // the warnings and notes reported while catching
+  static CAUGHT: RefCell<Vec<Diagnostic>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Level {
+  Error,
+  Warning,
+  Note,
+}
+
+impl Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Level::Error => write!(f, "error"),
+      Level::Warning => write!(f, "warning"),
+      Level::Note => write!(f, "note"),
+    }
+  }
+}
+
+// a diagnostic which was caught, rather than printed
+pub struct Diagnostic {
+  pub level: Level,
+  pub message: String,
+  // (file, line)
+  pub position: Option<(String, usize)>,
+}
+
+impl Diagnostic {
+  fn new(level: Level, message: String, position: Option<&Position>) -> Self {
+    Diagnostic {
+      level: level,
+      message: message,
+      position: position.map(|pos| (pos.file().to_owned(), pos.line)),
+    }
+  }
+}
+
+// runs `f', returning the first error instead of exiting the process, and
+// the warnings and notes instead of printing them; for when the assembler is
+// running inside something else, like rustc
+pub fn catch_errors<T, F: FnOnce() -> T>(
+  f: F,
+) -> (Result<T, Diagnostic>, Vec<Diagnostic>) {
+  let catching = CATCHING.with(|catching| catching.replace(true));
+  let caught = CAUGHT.with(|caught| caught.replace(Vec::new()));
+  let ret = panic::catch_unwind(panic::AssertUnwindSafe(f));
+  CATCHING.with(|cell| cell.set(catching));
+  let reported = CAUGHT.with(|cell| cell.replace(caught));
+  let ret = match ret {
+    Ok(ret) => Ok(ret),
+    Err(payload) => match payload.downcast::<Diagnostic>() {
+      Ok(error) => Err(*error),
+      Err(payload) => panic::resume_unwind(payload),
+    },
+  };
+  (ret, reported)
+}
+
+// what note! and warning! expand to
+pub fn report(level: Level, message: String, position: &Position) {
+  if CATCHING.with(|catching| catching.get()) {
+    let diagnostic = Diagnostic::new(level, message, Some(position));
+    CAUGHT.with(|caught| caught.borrow_mut().push(diagnostic));
+  } else {
+    eprintln!("{}: {} at {}", level, message, position);
+  }
+}
+
+// what error! and error_np! expand to
+pub fn fail(message: String, position: Option<&Position>, note: String) -> ! {
+  if CATCHING.with(|catching| catching.get()) {
+    // resume_unwind skips the panic hook, so nothing is printed
+    panic::resume_unwind(Box::new(
+      Diagnostic::new(Level::Error, message, position),
+    ));
+  }
+  match position {
+    Some(position) => eprintln!("error: {} at {}", message, position),
+    None => eprintln!("error: {}", message),
+  }
+  eprintln!("{}", note);
+  process::exit(1);
+}
@@ -1,90 +1,19 @@
+// `field: field` is the house style; it predates shorthand initialization
+#![allow(clippy::redundant_field_names)]
+
 use std::fs::File;
 use std::io::Write;
-use std::fmt::{self, Debug};
+use std::path::Path;
 
-use clap::{Arg, App};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 extern crate clap;
 #[macro_use]
-extern crate maplit;
-
-#[macro_use]
-mod macros;
-mod lexer;
-mod parser;
-
-pub struct Opcode {
-  var: OpcodeVariant,
-  reg: u16,
-  num: u16,
-}
-
-pub enum OpcodeVariant {
-  MoveImmediate,
-  Move,
-  MoveDeref,
-  Load,
-  Store,
-  Add,
-  Sub,
-  And,
-  Or,
-  Xor,
-  ShiftRight,
-  ShiftLeft,
-  ShiftArithmetic,
-  JumpGreater(u16),
-  JumpLesser(u16),
-  JumpEqual(u16),
-  Data(Vec<u16>), // does not use reg or num
-}
+extern crate assembler;
 
-impl Debug for Opcode {
-  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    use OpcodeVariant::*;
-    match self.var {
-      MoveImmediate => write!(f, "MI {}, {}", self.reg, self.num),
-      Move => write!(f, "MV {}, {}", self.reg, self.num),
-      MoveDeref => write!(f, "MD {}, {}", self.reg, self.num),
-      Load => write!(f, "LD {}, {}", self.reg, self.num),
-      Store => write!(f, "ST {}, {}", self.reg, self.num),
-      Add => write!(f, "AD {}, {}", self.reg, self.num),
-      Sub => write!(f, "SB {}, {}", self.reg, self.num),
-      And => write!(f, "ND {}, {}", self.reg, self.num),
-      Or => write!(f, "OR {}, {}", self.reg, self.num),
-      Xor => write!(f, "XR {}, {}", self.reg, self.num),
-      ShiftRight => write!(f, "SR {}, {}", self.reg, self.num),
-      ShiftLeft => write!(f, "SL {}, {}", self.reg, self.num),
-      ShiftArithmetic => write!(f, "SA {}, {}", self.reg, self.num),
-      JumpGreater(label) =>
-        write!(f, "JG {}, {}, {}", self.reg, self.num, label),
-      JumpLesser(label) =>
-        write!(f, "JL {}, {}, {}", self.reg, self.num, label),
-      JumpEqual(label) =>
-        write!(f, "JQ {}, {}, {}", self.reg, self.num, label),
-      Data(ref nums) => {
-        try!(write!(f, "DATA "));
-        for el in nums {
-          try!(write!(f, "{} ", el));
-        }
-        write!(f, "ENDDATA")
-      }
-    }
-  }
-}
-
-struct Program(Vec<Opcode>, parser::Parser);
-
-impl Program {
-  fn new(filename: &str) -> Self {
-    let mut parser = parser::Parser::new(filename);
-    let mut v = Vec::new();
-    while let Some(el) = parser.next() {
-      v.push(el);
-    }
-    Program(v, parser)
-  }
-}
+use assembler::{container, debugger, elf, export, parser, parse_number};
+use assembler::Program;
+use container::{Contents, Executable, Segment};
 
 fn main() {
   let matches =
@@ -92,6 +21,8 @@ fn main() {
       .version("0.1")
       .author("Nicole Mazzuca <npmazzuca@gmail.com>")
       .about("A work in progress assembler for the CT64k")
+      .setting(AppSettings::SubcommandsNegateReqs)
+      .setting(AppSettings::ArgsNegateSubcommands)
       .arg(
         Arg::with_name("output")
           .short("o")
@@ -109,63 +40,136 @@ fn main() {
           .short("p")
           .long("print-labels")
           .help("Sets whether the assembler prints the values of the labels")
+      ).args(&option_args())
+      .arg(
+        Arg::with_name("format")
+          .long("format")
+          .value_name("FORMAT")
+          .help(
+            "Sets the output format; raw is the bare words, loaded at \
+             0x1000, ct64k is the executable format in src/container.rs, \
+             and elf is ELF32, for binutils"
+          )
+          .possible_values(&["raw", "ct64k", "elf"])
+          .default_value("raw")
+          .takes_value(true),
+      ).arg(
+        Arg::with_name("symbols")
+          .long("symbols")
+          .help("Embeds the labels in the executable, with --format ct64k")
+      ).arg(
+        Arg::with_name("export")
+          .long("export")
+          .value_name("FILE")
+          .help(
+            "Writes the public constants and labels to FILE; a C header if \
+             it ends in .h, a rust module for .rs, and python for .py"
+          )
+          .takes_value(true)
+          .multiple(true)
+          .number_of_values(1),
+      ).arg(
+        Arg::with_name("export-prefix")
+          .long("export-prefix")
+          .value_name("PREFIX")
+          .help(
+            "Starts every name --export writes with PREFIX, so that a label \
+             like `main' doesn't clash with the host's"
+          )
+          .takes_value(true),
+      ).subcommand(
+        SubCommand::with_name("info")
+          .about("Describes a CT64k executable, or a CT64k ELF file")
+          .arg(
+            Arg::with_name("input")
+              .help("The executable to describe")
+              .required(true)
+              .index(1),
+          ),
+      ).subcommand(
+        SubCommand::with_name("debug")
+          .about("Assembles a program, and runs it in an interactive debugger")
+          .arg(
+            Arg::with_name("input")
+              .help("The source to debug")
+              .required(true)
+              .index(1),
+          ).args(&option_args()),
       ).get_matches();
 
+  if let Some(matches) = matches.subcommand_matches("info") {
+    return info(matches);
+  }
+  if let Some(matches) = matches.subcommand_matches("debug") {
+    let options = options(matches);
+    let program = Program::new(matches.value_of("input").unwrap(), &options);
+    return debugger::run(&program, options.stack_base);
+  }
+
   let outfilename = matches.value_of("output").unwrap();
   let inpfilename = matches.value_of("input").unwrap();
   let print_labels = matches.is_present("print-labels");
+  let options = parser::Options {
+    header_entry: matches.value_of("format").unwrap() != "raw",
+    ..options(&matches)
+  };
 
-  let program = Program::new(inpfilename);
-  let mut out = Vec::new();
-  for op in program.0 {
-    use OpcodeVariant::*;
-
-    fn write(out: &mut Vec<u8>, to_write: &[u16]) {
-      let res = out.write_all(
-        unsafe {
-          std::slice::from_raw_parts(
-            to_write.as_ptr() as *const u8,
-            to_write.len() * 2,
-          )
-        }
-      );
-      match res {
-        Ok(_) => {}
-        Err(e) => {
-          error_np!("Error while writing: {}", e);
-        }
+  let program = Program::new(inpfilename, &options);
+  let out = match matches.value_of("format").unwrap() {
+    "ct64k" => {
+      let (bss_start, bss_size) = program.parser.bss();
+      let mut segments = vec![Segment {
+        address: parser::INST_OFFSET_BASE,
+        contents: Contents::Data(program.image),
+      }];
+      if bss_size != 0 {
+        segments.push(Segment {
+          address: bss_start,
+          contents: Contents::Zero(bss_size),
+        });
       }
-    };
-    fn arith(out: &mut Vec<u8>, opcode: u16, reg: u16, mem: u16) {
-      write(out, &[(opcode << 12) | reg, mem]);
-    };
-    fn jump(out: &mut Vec<u8>, opcode: u16, reg: u16, mem: u16, label: u16) {
-      write(out, &[(opcode << 12) | reg, mem, label]);
-    };
+      let symbols = if matches.is_present("symbols") {
+        program.parser.symbols().into_iter()
+          .map(|(name, value)| (name.to_owned(), value))
+          .collect()
+      } else {
+        Vec::new()
+      };
+      Executable {
+        entry: program.parser.entry(),
+        stack: options.stack_base,
+        segments: segments,
+        symbols: symbols,
+      }.write()
+    },
+    "elf" => elf::write(&program.image, &elf::Layout {
+      base: parser::INST_OFFSET_BASE,
+      entry: program.parser.entry(),
+      text_end: program.parser.text_end(),
+      bss: program.parser.bss(),
+    }, &program.parser.symbol_table()),
+    _ => program.image.iter().flat_map(|word| word.to_ne_bytes()).collect(),
+  };
 
-    match op.var {
-      MoveImmediate => arith(&mut out, 0x0, op.reg, op.num),
-      Move => arith(&mut out, 0x1, op.reg, op.num),
-      MoveDeref => arith(&mut out, 0x2, op.reg, op.num),
-      Load => arith(&mut out, 0x3, op.reg, op.num),
-      Store => arith(&mut out, 0x4, op.reg, op.num),
-      Add => arith(&mut out, 0x5, op.reg, op.num),
-      Sub => arith(&mut out, 0x6, op.reg, op.num),
-      And => arith(&mut out, 0x7, op.reg, op.num),
-      Or => arith(&mut out, 0x8, op.reg, op.num),
-      Xor => arith(&mut out, 0x9, op.reg, op.num),
-      ShiftRight => arith(&mut out, 0xA, op.reg, op.num),
-      ShiftLeft => arith(&mut out, 0xB, op.reg, op.num),
-      ShiftArithmetic => arith(&mut out, 0xC, op.reg, op.num),
-      JumpGreater(label) => jump(&mut out, 0xD, op.reg, op.num, label),
-      JumpLesser(label) => jump(&mut out, 0xE, op.reg, op.num, label),
-      JumpEqual(label) => jump(&mut out, 0xF, op.reg, op.num, label),
-      Data(nums) => write(&mut out, &nums),
-    }
+  if print_labels {
+    program.parser.print_labels();
   }
 
-  if print_labels {
-    program.1.print_labels();
+  let exports = program.parser.exports();
+  for filename in matches.values_of("export").into_iter().flatten() {
+    let path = Path::new(filename);
+    let language = match export::Language::from_path(path) {
+      Some(language) => language,
+      None => error_np!(
+        "Can't tell what to export to `{}'; use .h, .rs or .py", filename,
+      ),
+    };
+    let prefix = matches.value_of("export-prefix").unwrap_or("");
+    let contents =
+      export::write(language, &exports, prefix, inpfilename, path);
+    if let Err(e) = std::fs::write(path, contents) {
+      error_np!("Failed to write `{}'\nError: {}", filename, e);
+    }
   }
 
   match File::create(outfilename) {
@@ -178,3 +182,138 @@ fn main() {
     ),
   };
 }
+
+// the options which change how a program is assembled; `debug' assembles
+// too, so it shares them
+fn option_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("pool-macros")
+      .long("pool-macros")
+      .help(
+        "Makes the built-in macros take their constants from the \
+         constant pool, which makes them shorter"
+      ),
+    Arg::with_name("no-legalize")
+      .long("no-legalize")
+      .help(
+        "Makes register memory above 0x0FFF an error, instead of \
+         rewriting the instruction to go through a scratch register"
+      ),
+    Arg::with_name("crt0")
+      .long("crt0")
+      .help(
+        "Starts the program with a stub which sets up the stack, calls \
+         the `entry', and halts when it returns"
+      ),
+    Arg::with_name("stack-base")
+      .long("stack-base")
+      .value_name("ADDRESS")
+      .help("Sets where the crt0 stub, or the debugger, starts the stack")
+      .default_value("0x300")
+      .takes_value(true),
+    Arg::with_name("exit")
+      .long("exit")
+      .value_name("LABEL")
+      .help("Makes the crt0 stub jump to LABEL, instead of halting")
+      .takes_value(true),
+    Arg::with_name("define")
+      .short("D")
+      .value_name("NAME[=VALUE]")
+      .help("Defines a constant, which is 1 if no value is given")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1),
+    Arg::with_name("encrypt")
+      .long("encrypt")
+      .value_name("KEY")
+      .help(
+        "Encrypts the `encrypt' regions, and starts the program with a \
+         stub which decrypts them"
+      )
+      .takes_value(true),
+    Arg::with_name("cipher")
+      .long("cipher")
+      .value_name("CIPHER")
+      .help("Sets how --encrypt encrypts")
+      .possible_values(&["xor", "swizzle"])
+      .default_value("xor")
+      .takes_value(true),
+  ]
+}
+
+fn options(matches: &ArgMatches) -> parser::Options {
+  parser::Options {
+    pool_macros: matches.is_present("pool-macros"),
+    no_legalize: matches.is_present("no-legalize"),
+    crt0: matches.is_present("crt0"),
+    stack_base: {
+      let base = matches.value_of("stack-base").unwrap();
+      match parse_number(base) {
+        Some(base) => base,
+        None => error_np!("Invalid stack base: {}", base),
+      }
+    },
+    exit: matches.value_of("exit").map(|s| s.to_owned()),
+    defines: matches.values_of("define").into_iter().flatten().map(|def| {
+      match def.find('=') {
+        Some(idx) => match parse_number(&def[idx + 1..]) {
+          Some(value) => (def[..idx].to_owned(), value),
+          None => error_np!("Invalid value for {}", &def[..idx]),
+        },
+        None => (def.to_owned(), 1),
+      }
+    }).collect(),
+    encrypt: matches.value_of("encrypt").map(|key| {
+      let cipher = match matches.value_of("cipher").unwrap() {
+        "swizzle" => parser::Cipher::Swizzle,
+        _ => parser::Cipher::Xor,
+      };
+      match parse_number(key) {
+        Some(key) => (cipher, key),
+        None => error_np!("Invalid key: {}", key),
+      }
+    }),
+    header_entry: false,
+  }
+}
+
+fn info(matches: &ArgMatches) {
+  let filename = matches.value_of("input").unwrap();
+  let bytes = match std::fs::read(filename) {
+    Ok(bytes) => bytes,
+    Err(e) => error_np!("Failed to read `{}'\nError: {}", filename, e),
+  };
+  let exe = if bytes.starts_with(b"\x7FELF") {
+    elf::read(&bytes, parser::Options::default().stack_base)
+  } else {
+    Executable::read(&bytes)
+  };
+  let exe = match exe {
+    Ok(exe) => exe,
+    Err(e) => error_np!("{}: {}", filename, e),
+  };
+  println!("entry: 0x{:04X}", exe.entry);
+  println!("stack: 0x{:04X}", exe.stack);
+  println!("segments:");
+  for segment in &exe.segments {
+    let kind = match segment.contents {
+      Contents::Data(_) => "data",
+      Contents::Zero(_) => "zero",
+    };
+    println!(
+      "  0x{:04X}..0x{:04X}  {}  {} words",
+      segment.address,
+      segment.address as u32 + segment.len() as u32,
+      kind,
+      segment.len(),
+    );
+  }
+  if exe.symbols.is_empty() {
+    println!("symbols: none");
+  } else {
+    println!("symbols:");
+    for (name, value) in &exe.symbols {
+      println!("  0x{:04X}  {}", value, name);
+    }
+  }
+}
@@ -3,10 +3,11 @@ use std::path::{Path, PathBuf};
 use {Opcode, OpcodeVariant};
 
 use lexer::{
-  self, Directive, DirectiveVar, Lexer, OpArg, OpArgVar, Position, Public,
+  self, Checksum, Comparison, Diagnostic, Directive, DirectiveVar, EnumKind,
+  Lexer, OpArg, OpArgVar, Position, Public,
 };
 
-const INST_OFFSET_BASE: u16 = 0x1000;
+pub const INST_OFFSET_BASE: u16 = 0x1000;
 
 const REG_IP: u16 = 0x0;
 const REG_SP: u16 = 0x1;
@@ -15,6 +16,70 @@ const REG_SC0: u16 = 0x3;
 const REG_SC1: u16 = 0x4;
 const REG_SC2: u16 = 0x5;
 const REG_SC3: u16 = 0x6;
+// __rt0 through __rt8; arguments and temporaries for the runtime routines
+const REG_RUNTIME: u16 = 0x7;
+const REG_RUNTIME_END: u16 = 0x10;
+// r00 through r47
+const REG_CALLEE_SAVE: u16 = 0x10;
+const REG_CALLEE_SAVE_END: u16 = 0x40;
+
+// routines which the assembler links in when they're used; see lower_muldiv
+const RUNTIME: &[(&str, &str)] = &[
+  ("mul", include_str!("runtime/mul.asm")),
+  ("divu", include_str!("runtime/divu.asm")),
+  ("divs", include_str!("runtime/divs.asm")),
+  ("decrypt", include_str!("runtime/decrypt.asm")),
+  ("decrypt_swizzle", include_str!("runtime/decrypt_swizzle.asm")),
+];
+
+// the ops which the passes lower, rather than the macros, and which take no
+// operands. along with the macros which take none, the lexer reads a `#'
+// after them as a comment
+const NO_OPERANDS: &[&str] = &[
+  "else", "endif", "endwhile", "repeat", "break", "continue", "endproc",
+  "encrypt", "endencrypt",
+];
+
+// how far the key moves for each word; see runtime/decrypt.asm
+const KEY_STEP: u16 = 0x9E37;
+
+// football/swizzle.asm's permutation of half-nibbles, as the (mask, shift)
+// pairs that undo it; negative shifts are to the right
+const UNSWIZZLE: [(u16, i8); 8] = [
+  (0x0003, 14), (0xC000, -2), (0x0300, 2), (0x3000, -4),
+  (0x0C00, -4), (0x000C, 2), (0x00C0, -4), (0x0030, -4),
+];
+
+// what a symbol's value is
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SymbolKind {
+  // an address in the program
+  Label,
+  // an equ, or another constant the assembler made
+  Equ,
+}
+
+#[derive(Copy, Clone)]
+pub enum Cipher {
+  // each word is xored with the key, which then moves on by KEY_STEP
+  Xor,
+  // xored like Xor, and then swizzled
+  Swizzle,
+}
+
+impl Cipher {
+  fn encrypt(self, word: u16, key: u16) -> u16 {
+    fn shift(word: u16, by: i8) -> u16 {
+      if by < 0 { word >> -by } else { word << by }
+    }
+    match self {
+      Cipher::Xor => word ^ key,
+      Cipher::Swizzle => UNSWIZZLE.iter().fold(0, |acc, &(mask, by)| {
+        acc | shift((word ^ key) & shift(mask, by), -by)
+      }),
+    }
+  }
+}
 
 #[derive(Copy, Clone)]
 enum BaseOp {
@@ -36,18 +101,165 @@ enum BaseOp {
   JumpEqual,
 }
 
+impl BaseOp {
+  // the built-in macro which is just this op
+  fn mnemonic(self) -> &'static str {
+    match self {
+      BaseOp::MoveImmediate => "mi",
+      BaseOp::Move => "mv",
+      BaseOp::MoveDeref => "md",
+      BaseOp::Load => "ld",
+      BaseOp::Store => "st",
+      BaseOp::Add => "ad",
+      BaseOp::Sub => "sb",
+      BaseOp::And => "nd",
+      BaseOp::Or => "or",
+      BaseOp::Xor => "xr",
+      BaseOp::ShiftRight => "sr",
+      BaseOp::ShiftLeft => "sl",
+      BaseOp::ShiftArithmetic => "sa",
+      BaseOp::JumpGreater => "jg",
+      BaseOp::JumpLesser => "jl",
+      BaseOp::JumpEqual => "jq",
+    }
+  }
+}
+
+pub struct Options {
+  // built-in macros take their constants from the constant pool, instead
+  // of loading them into sc0
+  pub pool_macros: bool,
+  // register memory above 0x0FFF is an error, instead of being rewritten
+  pub no_legalize: bool,
+  // start the program with a stub which sets up the stack, and calls the
+  // `entry'; see lower_entry
+  pub crt0: bool,
+  pub stack_base: u16,
+  // where the stub jumps when the entry returns, instead of halting
+  pub exit: Option<String>,
+  // constants given on the command line, which are what ifdef checks for
+  pub defines: Vec<(String, u16)>,
+  // encrypt the `encrypt' regions with this key, and decrypt them at
+  // startup; see lower_encrypt
+  pub encrypt: Option<(Cipher, u16)>,
+  // the executable's header says where the program starts, so the `entry'
+  // needn't be jumped to; see lower_entry
+  pub header_entry: bool,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      pool_macros: false,
+      no_legalize: false,
+      crt0: false,
+      // where the CYBERT manual says the stack is at boot
+      stack_base: 0x300,
+      exit: None,
+      defines: Vec::new(),
+      encrypt: None,
+      header_entry: false,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Default)]
+struct ArgUsage {
+  read: bool,
+  write: bool,
+  // used as a value, rather than as an address
+  immediate: bool,
+}
+
+// structured control flow; see lower_blocks
+enum Block {
+  If {
+    pos: Position,
+    // the else, or the endif once there's been an else
+    skip: String,
+    has_else: bool,
+  },
+  While {
+    pos: Position,
+    top: String,
+    end: String,
+  },
+  Repeat {
+    pos: Position,
+    top: String,
+    // where continue jumps to; the until
+    test: String,
+    end: String,
+  },
+}
+
+// the instructions one line of source became; see Parser::lines
+pub struct SourceLine {
+  pub start: u16,
+  pub end: u16,
+  pub pos: Position,
+  // what the line used; a macro, or an instruction
+  pub op: String,
+}
+
+// (number of arguments, expansion)
+type Macro = (u16, Vec<(BaseOp, Vec<OpArg>)>);
+
 pub struct Parser {
   op_buffer: Vec<Opcode>,
   op_buffer_idx: usize,
   inst_offset: u16,
   directives: Vec<Directive>,
   labels: HashMap<String, u16>,
-  macros: HashMap<String, (u16, Vec<(BaseOp, Vec<OpArg>)>)>,
+  // constants which are printed along with the labels
+  listed_constants: Vec<String>,
+  // emitted after the program; see build_const_pool
+  const_pool: Vec<u16>,
+  // (start, size) of what the loader has to zero past the end of the image
+  bss: (u16, u16),
+  // (label of the word, start, end, algorithm, position); see
+  // apply_checksums
+  checksums: Vec<(String, OpArg, OpArg, Checksum, Position)>,
+  // the cipher and key, and the (start, end) labels of each region; see
+  // apply_encryption
+  encryption: Option<(Cipher, u16)>,
+  encrypted: Vec<(String, String, Position)>,
+  // names made public by `public', the names which are labels, and where
+  // the program's instructions end; all are only known while the
+  // directives are around
+  public: Vec<String>,
+  addresses: Vec<String>,
+  text_end: u16,
+  // the `entry', when the header starts the program there; see lower_entry
+  entry: Option<OpArg>,
+  // every file which was read, starting with the one being assembled
+  sources: Vec<PathBuf>,
+  // filled in as the instructions are emitted
+  lines: Vec<SourceLine>,
+  macros: HashMap<String, Macro>,
+  // the number of hidden labels made so far
+  hidden_labels: usize,
   idx: usize,
 }
 
 impl Parser {
-  pub fn new(filename: &str) -> Self {
+  pub fn new(filename: &str, options: &Options) -> Self {
+    let path: PathBuf = match Path::new(filename).canonicalize() {
+      Ok(c) => c,
+      Err(_) => error_np!("Unable to open file: {}", filename),
+    };
+    let lexer = Lexer::new(&path);
+    Parser::with_lexer(path, lexer, options)
+  }
+
+  // assembles `source' as though it were the file at `path', which doesn't
+  // have to exist; imports are found next to it
+  pub fn from_source(path: &Path, source: &str, options: &Options) -> Self {
+    let lexer = Lexer::from_source(path.to_str().unwrap(), source);
+    Parser::with_lexer(path.to_owned(), lexer, options)
+  }
+
+  fn with_lexer(path: PathBuf, mut lexer: Lexer, options: &Options) -> Self {
     // compiler_defined_pos
     macro_rules! macro_op_arg {
       ($lexer:expr, $var:ident) => (
@@ -63,11 +275,6 @@ impl Parser {
         }
       );
     }
-    let path: PathBuf = match Path::new(filename).canonicalize() {
-      Ok(c) => c,
-      Err(_) => error_np!("Unable to open file: {}", filename),
-    };
-    let lexer = Lexer::new(&path);
     let mut this = Parser {
       op_buffer: Vec::new(),
       op_buffer_idx: 0,
@@ -82,6 +289,18 @@ impl Parser {
         "sc2".to_owned() => REG_SC2,
         "sc3".to_owned() => REG_SC3,
       },
+      listed_constants: Vec::new(),
+      const_pool: Vec::new(),
+      bss: (0, 0),
+      checksums: Vec::new(),
+      encryption: options.encrypt,
+      encrypted: Vec::new(),
+      public: Vec::new(),
+      addresses: Vec::new(),
+      text_end: INST_OFFSET_BASE,
+      entry: None,
+      sources: Vec::new(),
+      lines: Vec::new(),
       macros: hashmap! {
         "mi".to_owned() => (2, vec![
           (BaseOp::MoveImmediate, vec![
@@ -235,6 +454,8 @@ impl Parser {
             macro_op_arg!(lexer, Number(REG_SC0)),
           ]),
         ]),
+        // the immediate forms of the arithmetic ops. mi is already the
+        // immediate form of mv, so there's no mvi
         "adi".to_owned() => (2, vec![
           (BaseOp::MoveImmediate, vec![
             macro_op_arg!(lexer, Number(REG_SC0)),
@@ -255,6 +476,66 @@ impl Parser {
             macro_op_arg!(lexer, Number(REG_SC0)),
           ]),
         ]),
+        "ndi".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::And, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        "ori".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::Or, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        "xri".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        "sri".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::ShiftRight, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        "sli".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::ShiftLeft, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        "sai".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::ShiftArithmetic, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
         "push".to_owned() => (1, vec![
           (BaseOp::MoveImmediate, vec![
             macro_op_arg!(lexer, Number(REG_SC0)),
@@ -309,6 +590,33 @@ impl Parser {
             macro_op_arg!(lexer, MacroArg(0)),
           ])
         ]),
+        // call through a pointer
+        "callm".to_owned() => (1, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(1)),
+          ]),
+          (BaseOp::Add, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(6))),
+            )),
+          ]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ])
+        ]),
         "ret".to_owned() => (0, vec![
           (BaseOp::MoveDeref, vec![
             macro_op_arg!(lexer, Number(REG_SC1)),
@@ -327,159 +635,2729 @@ impl Parser {
             macro_op_arg!(lexer, Number(REG_SC1)),
           ])
         ]),
-      },
-      idx: 0,
-    };
-
-    this.get_directives(&mut vec![path], lexer);
-
-    // normal labels
-    let mut inst_offset = INST_OFFSET_BASE;
-    for dir in &this.directives {
-      match dir.var {
-        DirectiveVar::Label(ref s, ref _public) => {
-          // NOTE(ubsan): can optimize this to mem::replace(String::new())
-          match this.labels.insert(s.clone(), inst_offset) {
-            Some(_) => {
-              error!(dir.pos, "Attempted to redefine label: {}", s);
-            }
-            None => {}
-          }
-        }
-        DirectiveVar::Op(ref op, _) =>
-          inst_offset += this.size_of_op_str(&dir.pos, op),
-        DirectiveVar::Const(..) => {}
-        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
-        DirectiveVar::Public(_) => {
-          // TODO(ubsan): silently ignored for now
-        },
-        DirectiveVar::Import(_, _) => {},
-        DirectiveVar::Macro{..} => unimplemented!(),
-      }
-    }
-
-    // equ constants
-    let mut inst_offset = INST_OFFSET_BASE;
-    for dir in &this.directives {
-      match dir.var {
-        DirectiveVar::Label(..) => {}
-        DirectiveVar::Op(ref op, _) =>
-          inst_offset += this.size_of_op_str(&dir.pos, op),
-        DirectiveVar::Const(ref s, ref arg, ref _public) => {
-          let n = arg.evaluate(&this.labels, &[], inst_offset);
-          match this.labels.insert(s.clone(), n) {
-            Some(s) => error!(
-              arg.pos, "Attempted to redefine label: {}", s
-            ),
-            None => {},
-          }
-        }
-        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
-        DirectiveVar::Public(_) => {
-          // TODO(ubsan): silently ignored for now
-        },
-        DirectiveVar::Import(_, _) => {},
-        DirectiveVar::Macro{..} => unimplemented!(),
-      }
-    }
-
-    this
-  }
-
-  pub fn print_labels(&self) {
-    let mut labels = self.labels.iter().collect::<Vec<_>>();
-    labels.sort_by(|a, b| <_ as ::std::cmp::Ord>::cmp(&a.1, &b.1));
-    for (label, constant) in labels {
-      if *constant >= INST_OFFSET_BASE {
-        println!("{} = 0x{:X}", label, constant);
-      }
-    }
-  }
-
-  fn get_directives(&mut self, imports: &mut Vec<PathBuf>, mut lexer: Lexer) {
-    use std::path::{Path, PathBuf};
-    fn make_path(cur_path: &Path, pos: &Position, vec: Vec<String>) -> PathBuf {
-      assert!(!vec.is_empty(), "ICE: DirectiveVar::Import had an empty Vec");
-      let mut filename = PathBuf::new();
-      for dir in &vec {
-        filename.push(dir);
-      }
-      filename.set_extension("asm");
-
-      let mut ret = PathBuf::from(cur_path);
-      ret.set_file_name(filename);
-      match ret.canonicalize() {
-        Ok(c) => c,
-        Err(_) => error!(pos, "failure to open import: {}", {
-          let mut tmp = vec.iter().fold(String::new(), |mut s, el| {
-            s.push_str(&el); s.push('.'); s
-          });
-          tmp.pop();
-          tmp
-        })
-      }
-    }
-    fn push_unique(vec: &mut Vec<PathBuf>, to_push: PathBuf) -> bool {
-      if vec.contains(&to_push) {
-        false
-      } else {
-        vec.push(to_push);
-        true
-      }
-    }
-    let index = imports.len() - 1;
-    while let Some(dir) = lexer.next_directive() {
-      if let DirectiveVar::Import(path, _) = dir.var {
-        let path = make_path(&imports[index], &dir.pos, path);
-        if push_unique(imports, path) {
-          let new_lexer = lexer.new_file_lexer(&imports[imports.len() - 1]);
-          self.get_directives(imports, new_lexer);
-        }
-      } else {
-        self.directives.push(dir);
-      }
-    }
-  }
-
-  fn size_of_op_str(&self, pos: &Position, op: &str) -> u16 {
-    match self.macros.get(op) {
-      Some(&(_, ref ops)) => {
-        let mut acc = 0;
-        for &(ref op, ref _args) in ops {
-          acc += self.size_of_op(*op);
-        }
-        acc
-      }
-      None => error!(pos, "Unknown opcode: {}", op),
-    }
-  }
-
-  fn size_of_op(&self, op: BaseOp) -> u16 {
-    match op {
-      BaseOp::MoveImmediate | BaseOp::Move | BaseOp::MoveDeref | BaseOp::Load
-      | BaseOp::Store | BaseOp::Add | BaseOp::Sub | BaseOp::And | BaseOp::Or
-      | BaseOp::Xor | BaseOp::ShiftRight | BaseOp::ShiftLeft
-      | BaseOp::ShiftArithmetic => 2,
-      BaseOp::JumpGreater | BaseOp::JumpLesser | BaseOp::JumpEqual => 3,
-    }
-  }
-
-  fn next_directive(&mut self) -> Option<Directive> {
-    if self.idx < self.directives.len() {
-      let ret = ::std::mem::replace(&mut self.directives[self.idx], Directive {
-        var: DirectiveVar::Label(String::new(), Public::Private),
-        pos: Position::empty(),
-      });
-      self.idx += 1;
-      Some(ret)
-    } else {
-      None
-    }
-  }
-}
-
-impl Iterator for Parser {
-  type Item = Opcode;
+        // jq a, b, $ + 5; ji L
+        "jne".to_owned() => (3, vec![
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jge".to_owned() => (3, vec![
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jle".to_owned() => (3, vec![
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jz".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(0)),
+          ]),
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+        ]),
+        // unsigned, so anything but zero is greater than zero
+        "jnz".to_owned() => (2, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(0)),
+          ]),
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+        ]),
+        // flipping the sign bits turns a signed comparison into an unsigned
+        // one. sc0 and sc1 are loaded before the operands are read, so
+        // neither can be an operand; see next
+        "jgs".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, Number(0x8000)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(REG_SC1)),
+          ]),
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jls".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, Number(0x8000)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(REG_SC1)),
+          ]),
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        // compare against a constant
+        "jqi".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jgi".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jli".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jnei".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jgei".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jlei".to_owned() => (3, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        // push bp; mv bp, sp; adi sp, N
+        // after a call, [bp] is the caller's bp, [bp-1] the return address,
+        // and [bp-2] the last argument pushed. locals are [bp+1] to [bp+N]
+        "enter".to_owned() => (1, vec![
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(1)),
+          ]),
+          (BaseOp::Add, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_BP)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_BP)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+          (BaseOp::Add, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+        // mv sp, bp; pop bp
+        "leave".to_owned() => (0, vec![
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_BP)),
+          ]),
+          (BaseOp::MoveDeref, vec![
+            macro_op_arg!(lexer, Number(REG_BP)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, Number(1)),
+          ]),
+          (BaseOp::Sub, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+        ]),
+      },
+      hidden_labels: 0,
+      idx: 0,
+    };
+    for reg in REG_CALLEE_SAVE..REG_CALLEE_SAVE_END {
+      let name = format!("r{:02}", reg - REG_CALLEE_SAVE);
+      this.labels.insert(name, reg);
+    }
+    for reg in REG_RUNTIME..REG_RUNTIME_END {
+      this.labels.insert(format!("__rt{}", reg - REG_RUNTIME), reg);
+    }
+    for (name, value) in &options.defines {
+      if this.labels.insert(name.clone(), *value).is_some() {
+        error_np!("Attempted to redefine label: {}", name);
+      }
+      this.listed_constants.push(name.clone());
+    }
+
+    if options.pool_macros {
+      let one = || macro_op_arg!(lexer, Immediate(
+        Box::new(macro_op_arg!(lexer, Number(1))),
+      ));
+      this.macros.extend(hashmap! {
+        "inc".to_owned() => (1, vec![
+          (BaseOp::Add, vec![macro_op_arg!(lexer, MacroArg(0)), one()]),
+        ]),
+        "dec".to_owned() => (1, vec![
+          (BaseOp::Sub, vec![macro_op_arg!(lexer, MacroArg(0)), one()]),
+        ]),
+        "adi".to_owned() => (2, vec![
+          (BaseOp::Add, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "sbi".to_owned() => (2, vec![
+          (BaseOp::Sub, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "ndi".to_owned() => (2, vec![
+          (BaseOp::And, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "ori".to_owned() => (2, vec![
+          (BaseOp::Or, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "xri".to_owned() => (2, vec![
+          (BaseOp::Xor, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "sri".to_owned() => (2, vec![
+          (BaseOp::ShiftRight, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "sli".to_owned() => (2, vec![
+          (BaseOp::ShiftLeft, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "sai".to_owned() => (2, vec![
+          (BaseOp::ShiftArithmetic, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+          ]),
+        ]),
+        "push".to_owned() => (1, vec![
+          (BaseOp::Add, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+        ]),
+        "pop".to_owned() => (1, vec![
+          (BaseOp::MoveDeref, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::Sub, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+        ]),
+        "call".to_owned() => (1, vec![
+          (BaseOp::Add, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(6))),
+            )),
+          ]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+        ]),
+        "callm".to_owned() => (1, vec![
+          (BaseOp::Add, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_SC0)),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(6))),
+            )),
+          ]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_SC0)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(0)),
+          ]),
+        ]),
+        "ret".to_owned() => (0, vec![
+          (BaseOp::MoveDeref, vec![
+            macro_op_arg!(lexer, Number(REG_SC1)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::Sub, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, Number(REG_SC1)),
+          ]),
+        ]),
+        "jz".to_owned() => (2, vec![
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, Number(0))),
+            )),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+        ]),
+        "jnz".to_owned() => (2, vec![
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, Number(0))),
+            )),
+            macro_op_arg!(lexer, MacroArg(1)),
+          ]),
+        ]),
+        "jqi".to_owned() => (3, vec![
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jgi".to_owned() => (3, vec![
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jli".to_owned() => (3, vec![
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jnei".to_owned() => (3, vec![
+          (BaseOp::JumpEqual, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jgei".to_owned() => (3, vec![
+          (BaseOp::JumpLesser, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "jlei".to_owned() => (3, vec![
+          (BaseOp::JumpGreater, vec![
+            macro_op_arg!(lexer, MacroArg(0)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(1))),
+            )),
+            macro_op_arg!(lexer, ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(macro_op_arg!(lexer, Here)),
+              Box::new(macro_op_arg!(lexer, Number(5))),
+            )),
+          ]),
+          (BaseOp::MoveImmediate, vec![
+            macro_op_arg!(lexer, Number(REG_IP)),
+            macro_op_arg!(lexer, MacroArg(2)),
+          ]),
+        ]),
+        "enter".to_owned() => (1, vec![
+          (BaseOp::Add, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+          (BaseOp::Load, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_BP)),
+          ]),
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_BP)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::Add, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Immediate(
+              Box::new(macro_op_arg!(lexer, MacroArg(0))),
+            )),
+          ]),
+        ]),
+        "leave".to_owned() => (0, vec![
+          (BaseOp::Move, vec![
+            macro_op_arg!(lexer, Number(REG_SP)),
+            macro_op_arg!(lexer, Number(REG_BP)),
+          ]),
+          (BaseOp::MoveDeref, vec![
+            macro_op_arg!(lexer, Number(REG_BP)),
+            macro_op_arg!(lexer, Number(REG_SP)),
+          ]),
+          (BaseOp::Sub, vec![macro_op_arg!(lexer, Number(REG_SP)), one()]),
+        ]),
+      });
+    }
+
+    let no_operands = this.macros.iter()
+      .filter(|&(_, &(args, _))| args == 0)
+      .map(|(name, _)| name.as_str())
+      .chain(NO_OPERANDS.iter().cloned())
+      .map(|name| name.as_bytes().to_owned())
+      .collect();
+    lexer.set_no_operands(no_operands);
+
+    let mut runtime = RUNTIME.iter().map(|&(name, source)| {
+      let filename = format!("<runtime>/{}.asm", name);
+      let mut lexer = lexer.new_source_lexer(&filename, source);
+      let mut directives = Vec::new();
+      while let Some(dir) = lexer.next_directive() {
+        directives.push(dir);
+      }
+      (name, directives)
+    }).collect();
+
+    let mut sources = vec![path];
+    this.get_directives(&mut sources, lexer);
+    this.sources = sources;
+    this.lower_conditionals();
+    this.lower_types();
+    this.lower_random();
+    this.lower_checksums();
+    this.lower_entry(options);
+    this.lower_encrypt(&mut runtime);
+    this.lower_blocks();
+    this.elide_identities();
+    this.lower_muldiv(&mut runtime);
+    this.lower_switch();
+    this.lower_procs();
+    this.lower_indirect();
+
+    // legalizing instructions moves everything after them, which may push
+    // more register memory out of range
+    let labels = this.labels.clone();
+    loop {
+      this.labels = labels.clone();
+      if !this.layout() {
+        continue;
+      }
+      if options.no_legalize || !this.legalize() {
+        break;
+      }
+    }
+    this.check_asserts();
+    this.record_sections();
+
+    this
+  }
+
+  fn record_sections(&mut self) {
+    let mut inst_offset = INST_OFFSET_BASE;
+    for dir in &self.directives {
+      match dir.var {
+        DirectiveVar::Op(ref op, _) => {
+          inst_offset += self.size_of_op_str(&dir.pos, op);
+          // the runtime routines are linked after the data
+          if Parser::runtime_source(dir.pos.file()).is_none() {
+            self.text_end = inst_offset;
+          }
+        },
+        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
+        DirectiveVar::Reserve(words) => inst_offset += words,
+        DirectiveVar::Label(ref name, public) => {
+          if let Public::Public = public {
+            self.public.push(name.clone());
+          }
+          self.addresses.push(name.clone());
+        },
+        DirectiveVar::Const(ref name, _, Public::Public)
+        | DirectiveVar::Public(ref name) => self.public.push(name.clone()),
+        _ => {},
+      }
+    }
+  }
+
+  // assigns addresses to labels, evaluates equ constants, and places the
+  // constant pool. the pool goes in front of any trailing reserves, so its
+  // size is guessed from the last layout; returns whether the guess held
+  fn layout(&mut self) -> bool {
+    // once the labels fit, the offsets everywhere else fit too
+    fn advance(pos: &Position, inst_offset: u16, words: u16) -> u16 {
+      match inst_offset.checked_add(words) {
+        Some(inst_offset) => inst_offset,
+        None => error!(pos, "The program doesn't fit below 0x10000"),
+      }
+    }
+
+    let end = self.initialized_end();
+    let pool_len = self.const_pool.len() as u16;
+
+    // normal labels
+    let mut inst_offset = INST_OFFSET_BASE;
+    let mut pool_start = INST_OFFSET_BASE;
+    for (idx, dir) in self.directives.iter().enumerate() {
+      if idx == end {
+        pool_start = inst_offset;
+        inst_offset = advance(&dir.pos, inst_offset, pool_len);
+      }
+      match dir.var {
+        DirectiveVar::Label(ref s, ref _public) => {
+          // NOTE(ubsan): can optimize this to mem::replace(String::new())
+          if self.labels.insert(s.clone(), inst_offset).is_some() {
+            error!(dir.pos, "Attempted to redefine label: {}", s);
+          }
+        }
+        DirectiveVar::Op(ref op, _) => {
+          let size = self.size_of_op_str(&dir.pos, op);
+          inst_offset = advance(&dir.pos, inst_offset, size);
+        },
+        DirectiveVar::Const(..) => {}
+        DirectiveVar::Data(ref data) =>
+          inst_offset = advance(&dir.pos, inst_offset, data.len() as u16),
+        DirectiveVar::Reserve(words) =>
+          inst_offset = advance(&dir.pos, inst_offset, words),
+        DirectiveVar::Public(_) => {
+          // TODO(ubsan): silently ignored for now
+        },
+        DirectiveVar::Import(_, _) => {},
+        DirectiveVar::Assert(..) => {},
+        DirectiveVar::Macro{..} => unimplemented!(),
+        DirectiveVar::Struct(..) | DirectiveVar::StructData(..)
+        | DirectiveVar::Enum(..) | DirectiveVar::ReserveWords(..) =>
+          unreachable!("ICE: types should have been lowered"),
+        DirectiveVar::Checksum(..) =>
+          unreachable!("ICE: checksums should have been lowered"),
+        DirectiveVar::Random(..) | DirectiveVar::Seed(..) =>
+          unreachable!("ICE: random data should have been lowered"),
+        DirectiveVar::Diagnostic(..) =>
+          unreachable!("ICE: diagnostics should have been reported"),
+      }
+    }
+    if end == self.directives.len() {
+      pool_start = inst_offset;
+      inst_offset = match inst_offset.checked_add(pool_len) {
+        Some(inst_offset) => inst_offset,
+        None => error_np!("The program doesn't fit below 0x10000"),
+      };
+    }
+    let bss_start = pool_start + pool_len;
+    self.bss = (bss_start, inst_offset - bss_start);
+
+    // equ constants
+    let mut inst_offset = INST_OFFSET_BASE;
+    for (idx, dir) in self.directives.iter().enumerate() {
+      if idx == end {
+        inst_offset += pool_len;
+      }
+      match dir.var {
+        DirectiveVar::Label(..) => {}
+        DirectiveVar::Op(ref op, _) =>
+          inst_offset += self.size_of_op_str(&dir.pos, op),
+        DirectiveVar::Const(ref s, ref arg, ref _public) => {
+          let n = arg.evaluate(&self.labels, &[], inst_offset);
+          if let Some(s) = self.labels.insert(s.clone(), n) {
+            error!(arg.pos, "Attempted to redefine label: {}", s);
+          }
+        }
+        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
+        DirectiveVar::Reserve(words) => inst_offset += words,
+        DirectiveVar::Public(_) => {
+          // TODO(ubsan): silently ignored for now
+        },
+        DirectiveVar::Import(_, _) => {},
+        DirectiveVar::Assert(..) => {},
+        DirectiveVar::Macro{..} => unimplemented!(),
+        DirectiveVar::Struct(..) | DirectiveVar::StructData(..)
+        | DirectiveVar::Enum(..) | DirectiveVar::ReserveWords(..) =>
+          unreachable!("ICE: types should have been lowered"),
+        DirectiveVar::Checksum(..) =>
+          unreachable!("ICE: checksums should have been lowered"),
+        DirectiveVar::Random(..) | DirectiveVar::Seed(..) =>
+          unreachable!("ICE: random data should have been lowered"),
+        DirectiveVar::Diagnostic(..) =>
+          unreachable!("ICE: diagnostics should have been reported"),
+      }
+    }
+
+    self.build_const_pool(pool_start);
+    self.const_pool.len() as u16 == pool_len
+  }
+
+  // the index just past the last directive which emits anything; reserves
+  // from there on are left out of the image
+  fn initialized_end(&self) -> usize {
+    self.directives.iter()
+      .rposition(|dir| matches!(
+        dir.var, DirectiveVar::Op(..) | DirectiveVar::Data(..)
+      ))
+      .map_or(0, |idx| idx + 1)
+  }
+
+  // the encoding only has 12 bits for register memory. base ops whose
+  // register memory, A, is above 0x0FFF are rewritten to go through a scratch
+  // register, T, and a constant pool word holding A:
+  //   mi A, m     ->  mi T, m; st T, #A
+  //   mv A, m     ->  mv T, m; st T, #A
+  //   md A, m     ->  md T, m; st T, #A
+  //   ld A, m     ->  mv T, A; ld T, m
+  //   st A, m     ->  mv T, A; st T, m
+  //   op A, m     ->  mv T, A; op T, m; st T, #A
+  //   jX A, m, L  ->  mv T, A; jX T, m, L
+  // T is sc3, or sc2 if m is sc3. returns whether anything was rewritten
+  fn legalize(&mut self) -> bool {
+    fn substitute(arg: &OpArg, mac_args: &[OpArg]) -> OpArg {
+      let var = match arg.var {
+        OpArgVar::MacroArg(n) => return mac_args[n as usize].clone(),
+        OpArgVar::ArithOp(op, ref lhs, ref rhs) => OpArgVar::ArithOp(
+          op,
+          Box::new(substitute(lhs, mac_args)),
+          Box::new(substitute(rhs, mac_args)),
+        ),
+        OpArgVar::Immediate(ref value) =>
+          OpArgVar::Immediate(Box::new(substitute(value, mac_args))),
+        ref var => var.clone(),
+      };
+      OpArg {
+        var: var,
+        pos: arg.pos.clone(),
+      }
+    }
+
+    let mut changed = false;
+    let mut inst_offset = INST_OFFSET_BASE;
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let (op, mac_args) = match dir.var {
+        DirectiveVar::Op(ref op, ref mac_args) => (op, mac_args),
+        DirectiveVar::Data(ref data) => {
+          inst_offset += data.len() as u16;
+          self.directives.push(dir);
+          continue;
+        },
+        DirectiveVar::Reserve(words) => {
+          inst_offset += words;
+          self.directives.push(dir);
+          continue;
+        },
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      let &(size, ref ops) = match self.macros.get(op) {
+        Some(mac) => mac,
+        None => error!(dir.pos, "Unknown opcode: {}", op),
+      };
+      if mac_args.len() as u16 != size {
+        error!(
+          dir.pos,
+          "Invalid number of args to {}; expected {}, found {}",
+          op,
+          size,
+          mac_args.len(),
+        );
+      }
+
+      let mut offset = inst_offset;
+      let mut out_of_range = Vec::new();
+      for &(base, ref args) in ops {
+        let reg = args[0].evaluate(&self.labels, mac_args, offset);
+        out_of_range.push(reg >= 0x1000);
+        offset += self.size_of_op(base);
+      }
+      inst_offset = offset;
+      if !out_of_range.contains(&true) {
+        self.directives.push(dir);
+        continue;
+      }
+
+      changed = true;
+      let mut rewritten = Vec::new();
+      for (&(base, ref args), &legalize) in ops.iter().zip(&out_of_range) {
+        let mut args = args.iter()
+          .map(|arg| substitute(arg, mac_args))
+          .collect::<Vec<_>>();
+        let mut push = |op: &str, args: Vec<OpArg>| {
+          rewritten.push(Directive {
+            var: DirectiveVar::Op(op.to_owned(), args),
+            pos: dir.pos.clone(),
+          });
+        };
+        if !legalize {
+          push(base.mnemonic(), args);
+          continue;
+        }
+
+        let scratch = match base {
+          BaseOp::MoveImmediate => REG_SC3,
+          _ => match args[1].evaluate(&self.labels, &[], offset) {
+            REG_SC3 => REG_SC2,
+            _ => REG_SC3,
+          },
+        };
+        let scratch = OpArg {
+          var: OpArgVar::Number(scratch),
+          pos: args[0].pos.clone(),
+        };
+        let reg = ::std::mem::replace(&mut args[0], scratch.clone());
+        let pointer = OpArg {
+          pos: reg.pos.clone(),
+          var: OpArgVar::Immediate(Box::new(reg.clone())),
+        };
+        match base {
+          BaseOp::MoveImmediate | BaseOp::Move | BaseOp::MoveDeref => {
+            push(base.mnemonic(), args);
+            push("st", vec![scratch, pointer]);
+          },
+          BaseOp::Load | BaseOp::Store | BaseOp::JumpGreater
+          | BaseOp::JumpLesser | BaseOp::JumpEqual => {
+            push("mv", vec![scratch, reg]);
+            push(base.mnemonic(), args);
+          },
+          BaseOp::Add | BaseOp::Sub | BaseOp::And | BaseOp::Or | BaseOp::Xor
+          | BaseOp::ShiftRight | BaseOp::ShiftLeft
+          | BaseOp::ShiftArithmetic => {
+            push("mv", vec![scratch.clone(), reg]);
+            push(base.mnemonic(), args);
+            push("st", vec![scratch, pointer]);
+          },
+        }
+      }
+      self.directives.extend(rewritten);
+    }
+    changed
+  }
+
+  fn check_asserts(&self) {
+    let end = self.initialized_end();
+    let mut inst_offset = INST_OFFSET_BASE;
+    for (idx, dir) in self.directives.iter().enumerate() {
+      if idx == end {
+        inst_offset += self.const_pool.len() as u16;
+      }
+      match dir.var {
+        DirectiveVar::Op(ref op, _) =>
+          inst_offset += self.size_of_op_str(&dir.pos, op),
+        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
+        DirectiveVar::Reserve(words) => inst_offset += words,
+        DirectiveVar::Assert(ref cond, ref message) => {
+          let holds = match cond.var {
+            OpArgVar::Condition(cmp, ref lhs, ref rhs) => {
+              let lhs = lhs.evaluate(&self.labels, &[], inst_offset);
+              let rhs = rhs.evaluate(&self.labels, &[], inst_offset);
+              match cmp {
+                Comparison::Equal => lhs == rhs,
+                Comparison::NotEqual => lhs != rhs,
+                Comparison::Lesser => lhs < rhs,
+                Comparison::Greater => lhs > rhs,
+                Comparison::LesserEqual => lhs <= rhs,
+                Comparison::GreaterEqual => lhs >= rhs,
+              }
+            },
+            _ => cond.evaluate(&self.labels, &[], inst_offset) != 0,
+          };
+          if !holds {
+            match *message {
+              Some(ref message) =>
+                error!(dir.pos, "Assertion failed: {}", message),
+              None => error!(dir.pos, "Assertion failed"),
+            }
+          }
+        },
+        _ => {},
+      }
+    }
+  }
+
+  pub fn print_labels(&self) {
+    for (label, constant) in self.symbols() {
+      println!("{} = 0x{:X}", label, constant);
+    }
+  }
+
+  // the labels, and the constants worth listing with them, by value. the
+  // constant pool's words are only labelled so that #immediates find them,
+  // and hidden labels are the assembler's own; neither is listed
+  pub fn symbols(&self) -> Vec<(&str, u16)> {
+    let mut labels = self.labels.iter().filter(|&(label, &constant)| {
+      if label.starts_with('#') || label.starts_with('@') {
+        return false;
+      }
+      constant >= INST_OFFSET_BASE || self.listed_constants.contains(label)
+    }).map(|(label, &constant)| (&**label, constant)).collect::<Vec<_>>();
+    labels.sort_by(|a, b| <_ as ::std::cmp::Ord>::cmp(&(a.1, a.0), &(b.1, b.0)));
+    labels
+  }
+
+  // the public constants and labels, in the order they were made public
+  pub fn exports(&self) -> Vec<(&str, u16)> {
+    let mut exports: Vec<(&str, u16)> = Vec::new();
+    for name in &self.public {
+      if let Some(&value) = self.labels.get(name) {
+        if !exports.iter().any(|&(export, _)| export == name) {
+          exports.push((name, value));
+        }
+      }
+    }
+    exports
+  }
+
+  // symbols, plus the public constants, with whether they're public, and
+  // whether they're labels or constants
+  pub fn symbol_table(&self) -> Vec<(&str, u16, bool, SymbolKind)> {
+    let kind = |name: &str| {
+      if self.addresses.iter().any(|label| label == name) {
+        SymbolKind::Label
+      } else {
+        SymbolKind::Equ
+      }
+    };
+    let mut symbols = self.symbols().into_iter()
+      .map(|(name, value)| {
+        let public = self.public.iter().any(|public| public == name);
+        (name, value, public, kind(name))
+      })
+      .collect::<Vec<_>>();
+    for name in &self.public {
+      if let Some(&value) = self.labels.get(name) {
+        if !symbols.iter().any(|&(symbol, _, _, _)| symbol == name) {
+          symbols.push((name, value, true, kind(name)));
+        }
+      }
+    }
+    symbols
+  }
+
+  pub fn sources(&self) -> &[PathBuf] {
+    &self.sources
+  }
+
+  // where each instruction came from, by address; complete once every
+  // instruction has been emitted
+  pub fn lines(&self) -> &[SourceLine] {
+    &self.lines
+  }
+
+  pub fn label(&self, name: &str) -> Option<u16> {
+    self.labels.get(name).cloned()
+  }
+
+  // the source of a runtime routine, like `<runtime>/mul.asm', which
+  // isn't a file
+  pub fn runtime_source(filename: &str) -> Option<&'static str> {
+    RUNTIME.iter().find(|&&(name, _)| {
+      filename == format!("<runtime>/{}.asm", name)
+    }).map(|&(_, source)| source)
+  }
+
+  // where the program's own instructions end; everything after it is data,
+  // along with the runtime routines, which are linked after the data
+  pub fn text_end(&self) -> u16 {
+    self.text_end
+  }
+
+  // where the program starts; the `entry', if the header carries it
+  pub fn entry(&self) -> u16 {
+    match self.entry {
+      Some(ref entry) => entry.evaluate(&self.labels, &[], INST_OFFSET_BASE),
+      None => INST_OFFSET_BASE,
+    }
+  }
+
+  // (start, size) of what the loader has to zero past the end of the image
+  pub fn bss(&self) -> (u16, u16) {
+    self.bss
+  }
+
+  fn get_directives(&mut self, imports: &mut Vec<PathBuf>, mut lexer: Lexer) {
+    use std::path::{Path, PathBuf};
+    fn make_path(cur_path: &Path, pos: &Position, vec: Vec<String>) -> PathBuf {
+      assert!(!vec.is_empty(), "ICE: DirectiveVar::Import had an empty Vec");
+      let mut filename = PathBuf::new();
+      for dir in &vec {
+        filename.push(dir);
+      }
+      filename.set_extension("asm");
+
+      let mut ret = PathBuf::from(cur_path);
+      ret.set_file_name(filename);
+      match ret.canonicalize() {
+        Ok(c) => c,
+        Err(_) => error!(pos, "failure to open import: {}", {
+          let mut tmp = vec.iter().fold(String::new(), |mut s, el| {
+            s.push_str(el); s.push('.'); s
+          });
+          tmp.pop();
+          tmp
+        })
+      }
+    }
+    fn push_unique(vec: &mut Vec<PathBuf>, to_push: PathBuf) -> bool {
+      if vec.contains(&to_push) {
+        false
+      } else {
+        vec.push(to_push);
+        true
+      }
+    }
+    let index = imports.len() - 1;
+    while let Some(dir) = lexer.next_directive() {
+      if let DirectiveVar::Import(path, _) = dir.var {
+        let path = make_path(&imports[index], &dir.pos, path);
+        if push_unique(imports, path) {
+          let new_lexer = lexer.new_file_lexer(&imports[imports.len() - 1]);
+          self.get_directives(imports, new_lexer);
+        }
+      } else {
+        self.directives.push(dir);
+      }
+    }
+  }
+
+  // the equ constants which don't depend on any labels, for the passes
+  // which need values before layout
+  fn early_constants(&self) -> HashMap<String, u16> {
+    let mut constants = self.labels.clone();
+    loop {
+      let mut changed = false;
+      for dir in &self.directives {
+        if let DirectiveVar::Const(ref name, ref arg, _) = dir.var {
+          if constants.contains_key(name) {
+            continue;
+          }
+          if let Some(value) = arg.try_evaluate(&constants) {
+            constants.insert(name.clone(), value);
+            changed = true;
+          }
+        }
+      }
+      if !changed {
+        return constants;
+      }
+    }
+  }
+
+  // struct definitions become `Name.field` offset constants and a
+  // `Name.size` constant; struct initializers become plain data.
+  // enum and flags members become `Name.Member` constants. `res N' becomes
+  // a reserve of N words, where N may use those constants.
+  fn lower_types(&mut self) {
+    fn constant(arg: &OpArg, constants: &HashMap<String, u16>) -> u16 {
+      match arg.try_evaluate(constants) {
+        Some(n) => n,
+        None => error!(
+          arg.pos, "Expected a constant which doesn't depend on any labels",
+        ),
+      }
+    }
+
+    // sizes and values can use constants, and the types defined before them
+    let mut constants = self.early_constants();
+    let mut structs = HashMap::new();
+    for dir in &self.directives {
+      if let DirectiveVar::Enum(ref name, ref kind, ref members) = dir.var {
+        let (mut value, step) = match *kind {
+          EnumKind::Sequential(ref start, ref step) => (
+            constant(start, &constants),
+            Some(constant(step, &constants)),
+          ),
+          EnumKind::Flags(ref start) => {
+            let start = constant(start, &constants);
+            if !start.is_power_of_two() {
+              error!(dir.pos, "Flags must start at a power of two: {}", start);
+            }
+            (start, None)
+          },
+        };
+        // `value` is the next implicit value
+        for (member, explicit, pos) in members {
+          let n = match *explicit {
+            Some(ref arg) => constant(arg, &constants),
+            // the previous flag was 0x8000
+            None if step.is_none() && value == 0 =>
+              error!(pos, "Flag does not fit in 16 bits: {}", member),
+            None => value,
+          };
+          let label = format!("{}.{}", name, member);
+          if self.labels.insert(label.clone(), n).is_some() {
+            error!(pos, "Attempted to redefine label: {}", label);
+          }
+          constants.insert(label.clone(), n);
+          self.listed_constants.push(label);
+          value = match step {
+            Some(step) => n.wrapping_add(step),
+            None if explicit.is_none() => n.wrapping_shl(1),
+            // explicit flags may be zero or combinations; they only move
+            // the next flag past their highest bit
+            None if n == 0 => value,
+            None => {
+              let above = (0x8000u16 >> n.leading_zeros()).wrapping_shl(1);
+              if value != 0 && (above == 0 || above > value) {
+                above
+              } else {
+                value
+              }
+            },
+          };
+        }
+      } else if let DirectiveVar::Struct(ref name, ref fields) = dir.var {
+        let mut offset = 0u16;
+        let mut layout = Vec::new();
+        for (field, size) in fields {
+          if field == "size" {
+            error!(size.pos, "`size' is reserved, and can't be a field name");
+          }
+          let size = constant(size, &constants);
+          let label = format!("{}.{}", name, field);
+          if self.labels.insert(label.clone(), offset).is_some() {
+            error!(dir.pos, "Attempted to redefine label: {}", label);
+          }
+          constants.insert(label, offset);
+          layout.push((field.clone(), offset, size));
+          offset = match offset.checked_add(size) {
+            Some(o) => o,
+            None => error!(dir.pos, "Struct is too large: {}", name),
+          };
+        }
+        let label = format!("{}.size", name);
+        if self.labels.insert(label.clone(), offset).is_some() {
+          error!(dir.pos, "Attempted to redefine label: {}", label);
+        }
+        constants.insert(label, offset);
+        structs.insert(name.clone(), (offset, layout));
+      }
+    }
+
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      match dir.var {
+        DirectiveVar::Struct(..) | DirectiveVar::Enum(..) => {},
+        DirectiveVar::StructData(name, inits) => {
+          let &(size, ref layout) = match structs.get(&name) {
+            Some(s) => s,
+            None => error!(dir.pos, "Unknown struct: {}", name),
+          };
+          let zero = OpArg {
+            var: OpArgVar::Number(0),
+            pos: dir.pos.clone(),
+          };
+          let mut data = vec![zero; size as usize];
+          let mut seen = Vec::new();
+          for (field, init, pos) in inits {
+            let &(_, offset, size) =
+              match layout.iter().find(|f| f.0 == field) {
+                Some(f) => f,
+                None => error!(pos, "Struct {} has no field {}", name, field),
+              };
+            if seen.contains(&field) {
+              error!(pos, "Field initialized twice: {}", field);
+            }
+            if init.len() > size as usize {
+              error!(
+                pos,
+                "Too many words for field {}; expected at most {}, found {}",
+                field,
+                size,
+                init.len(),
+              );
+            }
+            for (i, arg) in init.into_iter().enumerate() {
+              data[offset as usize + i] = arg;
+            }
+            seen.push(field);
+          }
+          self.directives.push(Directive {
+            var: DirectiveVar::Data(data),
+            pos: dir.pos,
+          });
+        },
+        DirectiveVar::ReserveWords(words) => self.directives.push(Directive {
+          var: DirectiveVar::Reserve(constant(&words, &constants)),
+          pos: dir.pos,
+        }),
+        _ => self.directives.push(dir),
+      }
+    }
+  }
+
+  // ifdef NAME / ifndef NAME, else, and endif are resolved before anything
+  // else, and leave out the directives in the branch that isn't taken.
+  // error and warning directives are reported here, if they're not left out.
+  // runtime ifs share else and endif, so they're tracked too
+  fn lower_conditionals(&mut self) {
+    enum Cond {
+      Runtime,
+      Static {
+        pos: Position,
+        taken: bool,
+        has_else: bool,
+      },
+    }
+
+    let mut conds = Vec::new();
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let active = conds.iter().all(|cond| match *cond {
+        Cond::Runtime => true,
+        Cond::Static { taken, .. } => taken,
+      });
+      let op = match dir.var {
+        DirectiveVar::Op(ref op, _) => &**op,
+        DirectiveVar::Diagnostic(kind, ref message) => {
+          match kind {
+            _ if !active => {},
+            Diagnostic::Error => error!(dir.pos, "{}", message),
+            Diagnostic::Warning => warning!(dir.pos, "{}", message),
+          }
+          continue;
+        },
+        _ => "",
+      };
+      match op {
+        "ifdef" | "ifndef" => {
+          let name = match dir.var {
+            DirectiveVar::Op(_, ref args) => match args.as_slice() {
+              [OpArg { var: OpArgVar::Label(name), .. }] => name,
+              _ => error!(dir.pos, "Expected a single name for {}", op),
+            },
+            _ => unreachable!(),
+          };
+          let defined = self.labels.contains_key(name);
+          conds.push(Cond::Static {
+            pos: dir.pos.clone(),
+            taken: defined == (op == "ifdef"),
+            has_else: false,
+          });
+          continue;
+        },
+        "if" => conds.push(Cond::Runtime),
+        "else" => if let Some(&mut Cond::Static {
+          ref mut taken, ref mut has_else, ..
+        }) = conds.last_mut() {
+          if *has_else {
+            error!(dir.pos, "else without an ifdef");
+          }
+          *taken = !*taken;
+          *has_else = true;
+          continue;
+        },
+        "endif" => match conds.pop() {
+          Some(Cond::Static { .. }) => continue,
+          Some(Cond::Runtime) | None => {},
+        },
+        _ => {},
+      }
+      if active {
+        self.directives.push(dir);
+      }
+    }
+    for cond in conds {
+      if let Cond::Static { pos, .. } = cond {
+        error!(pos, "Unterminated ifdef");
+      }
+    }
+  }
+
+  // `entry LABEL' starts the program with `ji LABEL'. with crt0, it instead
+  // starts with:
+  //   mi sp, STACK_BASE
+  //   mi bp, STACK_BASE
+  //   call LABEL
+  //   hf          ; or `ji EXIT'
+  // with header_entry, the `ji' is left out, and the header starts the
+  // program at LABEL instead; unless the decryptor has to run first
+  fn lower_entry(&mut self, options: &Options) {
+    let mut entry = None;
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      match dir.var {
+        DirectiveVar::Op(ref op, ref args) if op == "entry" => {
+          if entry.is_some() {
+            error!(dir.pos, "Only one entry is allowed");
+          }
+          match args.as_slice() {
+            [arg] => entry = Some(arg.clone()),
+            _ => error!(dir.pos, "Expected a single label for entry"),
+          }
+        },
+        _ => self.directives.push(dir),
+      }
+    }
+
+    let entry = match entry {
+      Some(entry) => entry,
+      None if options.crt0 => error_np!("crt0 needs an `entry' directive"),
+      None => return,
+    };
+    if options.header_entry && !options.crt0 && options.encrypt.is_none() {
+      self.entry = Some(entry);
+      return;
+    }
+    let pos = entry.pos.clone();
+    let arg = |var| OpArg {
+      var: var,
+      pos: pos.clone(),
+    };
+    let op = |op: &str, args| Directive {
+      var: DirectiveVar::Op(op.to_owned(), args),
+      pos: pos.clone(),
+    };
+    let stub = if options.crt0 {
+      let stack_base = || arg(OpArgVar::Number(options.stack_base));
+      vec![
+        op("mi", vec![arg(OpArgVar::Number(REG_SP)), stack_base()]),
+        op("mi", vec![arg(OpArgVar::Number(REG_BP)), stack_base()]),
+        op("call", vec![entry]),
+        match options.exit {
+          Some(ref exit) => op("ji", vec![arg(OpArgVar::Label(exit.clone()))]),
+          None => op("hf", vec![]),
+        },
+      ]
+    } else {
+      vec![op("ji", vec![entry])]
+    };
+    self.directives.splice(0..0, stub);
+  }
+
+  // if a == b       jne a, b, @else
+  //   ...            ...
+  // else             ji @endif
+  //   ...          @else:
+  // endif            ...
+  //                @endif:
+  //
+  // while a < b    @while:
+  //   ...            jge a, b, @endwhile
+  // endwhile         ...
+  //                  ji @while
+  //                @endwhile:
+  //
+  // repeat         @repeat:
+  //   ...            ...
+  // until a > b    @until:
+  //                  jle a, b, @repeat
+  //                @endrepeat:
+  //
+  // break jumps to the end of the innermost loop, and continue to its
+  // condition
+  fn lower_blocks(&mut self) {
+    fn condition(dir: &Directive) -> (Comparison, OpArg, OpArg) {
+      match dir.var {
+        DirectiveVar::Op(ref op, ref args) => match args.as_slice() {
+          [OpArg { var: OpArgVar::Condition(cmp, lhs, rhs), .. }] =>
+            (*cmp, (**lhs).clone(), (**rhs).clone()),
+          _ => error!(dir.pos, "Expected a comparison after {}", op),
+        },
+        _ => unreachable!(),
+      }
+    }
+    // jumps to target if the comparison is false
+    fn unless(
+      cmp: Comparison, lhs: OpArg, rhs: OpArg, target: &str, pos: &Position,
+    ) -> Directive {
+      let op = match cmp {
+        Comparison::Equal => "jne",
+        Comparison::NotEqual => "jq",
+        Comparison::Lesser => "jge",
+        Comparison::Greater => "jle",
+        Comparison::LesserEqual => "jg",
+        Comparison::GreaterEqual => "jl",
+      };
+      let target = OpArg {
+        var: OpArgVar::Label(target.to_owned()),
+        pos: pos.clone(),
+      };
+      Directive {
+        var: DirectiveVar::Op(op.to_owned(), vec![lhs, rhs, target]),
+        pos: pos.clone(),
+      }
+    }
+    fn jump(target: &str, pos: &Position) -> Directive {
+      Directive {
+        var: DirectiveVar::Op("ji".to_owned(), vec![OpArg {
+          var: OpArgVar::Label(target.to_owned()),
+          pos: pos.clone(),
+        }]),
+        pos: pos.clone(),
+      }
+    }
+    fn label(name: String, pos: &Position) -> Directive {
+      Directive {
+        var: DirectiveVar::Label(name, Public::Private),
+        pos: pos.clone(),
+      }
+    }
+    fn name(block: &Block) -> &'static str {
+      match *block {
+        Block::If { .. } => "if",
+        Block::While { .. } => "while",
+        Block::Repeat { .. } => "repeat",
+      }
+    }
+    fn opener(block: &Block) -> &Position {
+      match *block {
+        Block::If { ref pos, .. } | Block::While { ref pos, .. }
+        | Block::Repeat { ref pos, .. } => pos,
+      }
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let op = match dir.var {
+        DirectiveVar::Op(ref op, _) => op.clone(),
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      let no_args = || if let DirectiveVar::Op(_, ref args) = dir.var {
+        if !args.is_empty() {
+          error!(dir.pos, "{} doesn't take any arguments", op);
+        }
+      };
+      let pos = dir.pos.clone();
+      match &*op {
+        "if" => {
+          let (cmp, lhs, rhs) = condition(&dir);
+          let skip = self.hidden_label("else");
+          self.directives.push(unless(cmp, lhs, rhs, &skip, &pos));
+          blocks.push(Block::If { pos: pos, skip: skip, has_else: false });
+        },
+        "else" => {
+          no_args();
+          let end = self.hidden_label("endif");
+          match blocks.last_mut() {
+            Some(&mut Block::If { ref mut skip, ref mut has_else, .. })
+            if !*has_else => {
+              self.directives.push(jump(&end, &pos));
+              let els = ::std::mem::replace(skip, end);
+              self.directives.push(label(els, &pos));
+              *has_else = true;
+            },
+            _ => error!(pos, "else without an if"),
+          }
+        },
+        "endif" => {
+          no_args();
+          match blocks.pop() {
+            Some(Block::If { skip, .. }) =>
+              self.directives.push(label(skip, &pos)),
+            Some(block) => error!(
+              opener(&block), "{} is closed by endif", name(&block),
+            ),
+            None => error!(pos, "endif without an if"),
+          }
+        },
+        "while" => {
+          let (cmp, lhs, rhs) = condition(&dir);
+          let top = self.hidden_label("while");
+          let end = self.hidden_label("endwhile");
+          self.directives.push(label(top.clone(), &pos));
+          self.directives.push(unless(cmp, lhs, rhs, &end, &pos));
+          blocks.push(Block::While { pos: pos, top: top, end: end });
+        },
+        "endwhile" => {
+          no_args();
+          match blocks.pop() {
+            Some(Block::While { top, end, .. }) => {
+              self.directives.push(jump(&top, &pos));
+              self.directives.push(label(end, &pos));
+            },
+            Some(block) => error!(
+              opener(&block), "{} is closed by endwhile", name(&block),
+            ),
+            None => error!(pos, "endwhile without a while"),
+          }
+        },
+        "repeat" => {
+          no_args();
+          let top = self.hidden_label("repeat");
+          let test = self.hidden_label("until");
+          let end = self.hidden_label("endrepeat");
+          self.directives.push(label(top.clone(), &pos));
+          blocks.push(Block::Repeat {
+            pos: pos,
+            top: top,
+            test: test,
+            end: end,
+          });
+        },
+        "until" => {
+          let (cmp, lhs, rhs) = condition(&dir);
+          match blocks.pop() {
+            Some(Block::Repeat { top, test, end, .. }) => {
+              self.directives.push(label(test, &pos));
+              self.directives.push(unless(cmp, lhs, rhs, &top, &pos));
+              self.directives.push(label(end, &pos));
+            },
+            Some(block) => error!(
+              opener(&block), "{} is closed by until", name(&block),
+            ),
+            None => error!(pos, "until without a repeat"),
+          }
+        },
+        "break" | "continue" => {
+          no_args();
+          let target = blocks.iter().rev().filter_map(|block| match *block {
+            Block::If { .. } => None,
+            Block::While { ref top, ref end, .. } =>
+              Some(if op == "break" { end } else { top }),
+            Block::Repeat { ref test, ref end, .. } =>
+              Some(if op == "break" { end } else { test }),
+          }).next();
+          match target {
+            Some(target) => self.directives.push(jump(target, &pos)),
+            None => error!(pos, "{} outside of a loop", op),
+          }
+        },
+        _ => self.directives.push(dir),
+      }
+    }
+    if let Some(block) = blocks.pop() {
+      error!(opener(&block), "Unterminated {}", name(&block));
+    }
+  }
+
+  // `adi x, 0', `ndi x, 0xFFFF', etc. don't do anything; if the constant is
+  // already known, they're removed
+  fn elide_identities(&mut self) {
+    let constants = self.early_constants();
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      if let DirectiveVar::Op(ref op, ref args) = dir.var {
+        let identity = match &**op {
+          "adi" | "sbi" | "ori" | "xri" | "sri" | "sli" | "sai" => Some(0),
+          "ndi" => Some(0xFFFF),
+          _ => None,
+        };
+        let value = match args.get(1) {
+          Some(arg) if args.len() == 2 => arg.try_evaluate(&constants),
+          _ => None,
+        };
+        if let (Some(identity), Some(value)) = (identity, value) {
+          if identity == value {
+            note!(
+              dir.pos,
+              "`{}' by 0x{:X} has no effect, and was removed",
+              op,
+              value,
+            );
+            continue;
+          }
+        }
+      }
+      self.directives.push(dir);
+    }
+  }
+
+  // mul, divu, modu, divs and mods. multiplying by a `#constant' that's
+  // already known, and unsigned division by a power of two, are done inline;
+  // everything else calls one of the routines in src/runtime, which are
+  // appended to the program if they're used:
+  //   mv __rt0, x
+  //   mv __rt1, y
+  //   mi __rt2, $ + 4
+  //   ji __divu
+  //   mv x, __rt1
+  fn lower_muldiv(&mut self, runtime: &mut HashMap<&str, Vec<Directive>>) {
+    let constants = self.early_constants();
+    let directives = ::std::mem::take(&mut self.directives);
+    let mut linked = Vec::new();
+    for dir in directives {
+      let (op, mut args) = match dir.var {
+        DirectiveVar::Op(ref op, ref args) => match &**op {
+          "mul" | "divu" | "modu" | "divs" | "mods" =>
+            (op.clone(), args.clone()),
+          _ => {
+            self.directives.push(dir);
+            continue;
+          },
+        },
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      if args.len() != 2 {
+        error!(
+          dir.pos,
+          "Invalid number of args to {}; expected 2, found {}",
+          op,
+          args.len(),
+        );
+      }
+      let src = args.pop().unwrap();
+      let dst = args.pop().unwrap();
+      let constant = match src.var {
+        OpArgVar::Immediate(ref value) => value.try_evaluate(&constants),
+        _ => None,
+      };
+      // the inline forms work in sc0 and sc1
+      let inline = match (&*op, constant) {
+        ("mul", Some(_)) => true,
+        ("divu", Some(k)) | ("modu", Some(k)) => k.is_power_of_two(),
+        _ => false,
+      };
+      if inline {
+        match dst.try_evaluate(&constants) {
+          Some(REG_SC0) | Some(REG_SC1) =>
+            error!(dst.pos, "sc0 and sc1 can't be the destination of {}", op),
+          _ => {},
+        }
+      }
+
+      let pos = dir.pos.clone();
+      let num = |n| OpArg {
+        var: OpArgVar::Number(n),
+        pos: pos.clone(),
+      };
+      let mut push = |op: &str, args: Vec<OpArg>| {
+        self.directives.push(Directive {
+          var: DirectiveVar::Op(op.to_owned(), args),
+          pos: pos.clone(),
+        });
+      };
+      match (&*op, constant) {
+        ("mul", Some(k)) => {
+          // x * -k is cheaper if k has fewer bits set
+          let neg = k.wrapping_neg();
+          let (k, negate) = if k.count_ones() > neg.count_ones() + 1 {
+            (neg, true)
+          } else {
+            (k, false)
+          };
+          if k == 0 {
+            push("mi", vec![dst.clone(), num(0)]);
+          } else if k != 1 {
+            // horner's method, from the highest set bit down
+            let top = 15 - k.leading_zeros() as u16;
+            push("mv", vec![num(REG_SC1), dst.clone()]);
+            let mut shift = 0;
+            for bit in (0..top).rev() {
+              shift += 1;
+              if k & (1 << bit) != 0 {
+                push("mi", vec![num(REG_SC0), num(shift)]);
+                push("sl", vec![dst.clone(), num(REG_SC0)]);
+                push("ad", vec![dst.clone(), num(REG_SC1)]);
+                shift = 0;
+              }
+            }
+            if shift != 0 {
+              push("mi", vec![num(REG_SC0), num(shift)]);
+              push("sl", vec![dst.clone(), num(REG_SC0)]);
+            }
+          }
+          if negate {
+            push("neg", vec![dst]);
+          }
+        },
+        ("divu", Some(k)) if k.is_power_of_two() => {
+          push("mi", vec![num(REG_SC0), num(k.trailing_zeros() as u16)]);
+          push("sr", vec![dst, num(REG_SC0)]);
+        },
+        ("modu", Some(k)) if k.is_power_of_two() => {
+          push("mi", vec![num(REG_SC0), num(k - 1)]);
+          push("nd", vec![dst, num(REG_SC0)]);
+        },
+        (op, _) => {
+          let (routine, result) = match op {
+            "mul" => ("mul", REG_RUNTIME),
+            "divu" => ("divu", REG_RUNTIME),
+            "modu" => ("divu", REG_RUNTIME + 1),
+            "divs" => ("divs", REG_RUNTIME),
+            _ => ("divs", REG_RUNTIME + 1),
+          };
+          let ret = OpArg {
+            var: OpArgVar::ArithOp(
+              lexer::ArithOp::Add,
+              Box::new(OpArg { var: OpArgVar::Here, pos: pos.clone() }),
+              Box::new(num(4)),
+            ),
+            pos: pos.clone(),
+          };
+          let target = OpArg {
+            var: OpArgVar::Label(format!("__{}", routine)),
+            pos: pos.clone(),
+          };
+          push("mv", vec![num(REG_RUNTIME), dst.clone()]);
+          push("mv", vec![num(REG_RUNTIME + 1), src]);
+          push("mi", vec![num(REG_RUNTIME + 2), ret]);
+          push("ji", vec![target]);
+          push("mv", vec![dst, num(result)]);
+
+          let deps: &[&str] = match routine {
+            "divs" => &["divs", "divu"],
+            _ => &[routine],
+          };
+          for dep in deps {
+            if !linked.contains(dep) {
+              linked.push(*dep);
+            }
+          }
+        },
+      }
+    }
+    for routine in linked {
+      self.link(runtime.remove(routine).unwrap());
+    }
+  }
+
+  // runtime routines go at the end of what's emitted, so they don't pull
+  // trailing reserves into the image. an encrypt region which ends the
+  // program keeps its end label in front of them; the decryptor can't
+  // decrypt itself
+  fn link(&mut self, routine: Vec<Directive>) {
+    let mut end = self.initialized_end();
+    while let Some(dir) = self.directives.get(end) {
+      match dir.var {
+        DirectiveVar::Label(ref name, _)
+          if self.encrypted.iter().any(|region| region.1 == *name) =>
+          end += 1,
+        _ => break,
+      }
+    }
+    self.directives.splice(end..end, routine);
+  }
+
+  // `encrypt' ... `endencrypt' regions are encrypted once everything is
+  // encoded, and decrypted by a stub at the start of the program:
+  //   mi __rt3, KEY
+  //   mi __rt0, @encrypt     ; for each region
+  //   mi __rt1, @endencrypt
+  //   mi __rt2, @decrypted
+  //   ji __decrypt
+  // @decrypted:
+  // without --encrypt, the regions are left as they are
+  fn lower_encrypt(&mut self, runtime: &mut HashMap<&str, Vec<Directive>>) {
+    let routine = match self.encryption {
+      Some((Cipher::Xor, _)) | None => "decrypt",
+      Some((Cipher::Swizzle, _)) => "decrypt_swizzle",
+    };
+    let mut open: Option<(Position, String)> = None;
+    let mut stub = Vec::new();
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let is_start = match dir.var {
+        DirectiveVar::Op(ref op, ref args) if op == "encrypt" => {
+          if !args.is_empty() {
+            error!(dir.pos, "encrypt doesn't take any arguments");
+          }
+          true
+        },
+        DirectiveVar::Op(ref op, ref args) if op == "endencrypt" => {
+          if !args.is_empty() {
+            error!(dir.pos, "endencrypt doesn't take any arguments");
+          }
+          false
+        },
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      if self.encryption.is_none() {
+        continue;
+      }
+
+      let pos = dir.pos;
+      let label = if is_start {
+        if let Some((ref pos, _)) = open {
+          error!(pos, "encrypt regions can't nest");
+        }
+        let start = self.hidden_label("encrypt");
+        open = Some((pos.clone(), start.clone()));
+        start
+      } else {
+        let start = match open.take() {
+          Some((_, start)) => start,
+          None => error!(pos, "endencrypt without an encrypt"),
+        };
+        let end = self.hidden_label("endencrypt");
+        let decrypted = self.hidden_label("decrypted");
+        let arg = |var| OpArg {
+          var: var,
+          pos: pos.clone(),
+        };
+        let label = |name: &String| arg(OpArgVar::Label(name.clone()));
+        let reg = |n| arg(OpArgVar::Number(REG_RUNTIME + n));
+        let op = |op: &str, args| Directive {
+          var: DirectiveVar::Op(op.to_owned(), args),
+          pos: pos.clone(),
+        };
+        stub.push(op("mi", vec![reg(0), label(&start)]));
+        stub.push(op("mi", vec![reg(1), label(&end)]));
+        stub.push(op("mi", vec![reg(2), label(&decrypted)]));
+        let target = arg(OpArgVar::Label(format!("__{}", routine)));
+        stub.push(op("ji", vec![target]));
+        stub.push(Directive {
+          var: DirectiveVar::Label(decrypted, Public::Private),
+          pos: pos.clone(),
+        });
+        self.encrypted.push((start, end.clone(), pos.clone()));
+        end
+      };
+      self.directives.push(Directive {
+        var: DirectiveVar::Label(label, Public::Private),
+        pos: pos,
+      });
+    }
+
+    let key = match self.encryption {
+      Some((_, key)) => key,
+      None => return,
+    };
+    if let Some((pos, _)) = open {
+      error!(pos, "Unterminated encrypt");
+    }
+    if self.encrypted.is_empty() {
+      error_np!("--encrypt needs an `encrypt' region");
+    }
+    let pos = stub[0].pos.clone();
+    stub.insert(0, Directive {
+      var: DirectiveVar::Op("mi".to_owned(), vec![
+        OpArg {
+          var: OpArgVar::Number(REG_RUNTIME + 3),
+          pos: pos.clone(),
+        },
+        OpArg {
+          var: OpArgVar::Number(key),
+          pos: pos.clone(),
+        },
+      ]),
+      pos: pos,
+    });
+    self.directives.splice(0..0, stub);
+    self.link(runtime.remove(routine).unwrap());
+  }
+
+  // encrypts the image the way lower_encrypt's stub decrypts it; after the
+  // checksums, which are over what the program sees
+  pub fn apply_encryption(&self, image: &mut [u16]) {
+    let (cipher, mut key) = match self.encryption {
+      Some(encryption) => encryption,
+      None => return,
+    };
+    for (start, end, pos) in &self.encrypted {
+      let start = (self.labels[start] - INST_OFFSET_BASE) as usize;
+      let end = (self.labels[end] - INST_OFFSET_BASE) as usize;
+      if end > image.len() {
+        error!(pos, "An encrypt region can't hold trailing reserves");
+      }
+      for word in &mut image[start..end] {
+        *word = cipher.encrypt(*word, key);
+        key = key.wrapping_add(KEY_STEP);
+      }
+    }
+  }
+
+  // `data rand N' becomes N words from a xorshift32 generator, seeded by
+  // the last `seed' directive, or by the SEED constant before the first one.
+  // seeds must be known before layout, so they're constants which don't
+  // depend on any labels: numbers, equs of them, or -D defines
+  fn lower_random(&mut self) {
+    fn seeded(seed: u16) -> u32 {
+      // xorshift is stuck at zero, so the seed is mixed into nonzero bits
+      0x2545_F491 ^ seed as u32
+    }
+
+    let constants = self.early_constants();
+    let mut state = constants.get("SEED").map(|&seed| seeded(seed));
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      match dir.var {
+        DirectiveVar::Seed(ref seed) => match seed.try_evaluate(&constants) {
+          Some(seed) => state = Some(seeded(seed)),
+          None => error!(
+            seed.pos,
+            "The seed must be a constant which doesn't depend on any labels",
+          ),
+        },
+        DirectiveVar::Random(words) => {
+          let state = match state {
+            Some(ref mut state) => state,
+            None => error!(
+              dir.pos,
+              "Random data needs a `seed' directive, or a SEED constant",
+            ),
+          };
+          let data = (0..words).map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            OpArg {
+              var: OpArgVar::Number((*state >> 16) as u16),
+              pos: dir.pos.clone(),
+            }
+          }).collect();
+          self.directives.push(Directive {
+            var: DirectiveVar::Data(data),
+            pos: dir.pos,
+          });
+        },
+        _ => self.directives.push(dir),
+      }
+    }
+  }
+
+  // checksum NAME, START, END, ALGORITHM
+  // NAME:
+  //   data 0
+  // the word is filled in by apply_checksums, once everything is encoded
+  fn lower_checksums(&mut self) {
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      match dir.var {
+        DirectiveVar::Checksum(name, start, end, algorithm) => {
+          self.directives.push(Directive {
+            var: DirectiveVar::Label(name.clone(), Public::Private),
+            pos: dir.pos.clone(),
+          });
+          self.directives.push(Directive {
+            var: DirectiveVar::Data(vec![OpArg {
+              var: OpArgVar::Number(0),
+              pos: dir.pos.clone(),
+            }]),
+            pos: dir.pos.clone(),
+          });
+          self.checksums.push((name, start, end, algorithm, dir.pos));
+        },
+        var => self.directives.push(Directive {
+          var: var,
+          pos: dir.pos,
+        }),
+      }
+    }
+  }
+
+  // computes each checksum over the encoded image, which starts at
+  // INST_OFFSET_BASE, in source order; END is exclusive
+  pub fn apply_checksums(&self, image: &mut [u16]) {
+    let image_end = INST_OFFSET_BASE as usize + image.len();
+    for &(ref name, ref start, ref end, algorithm, ref pos) in &self.checksums {
+      let address = self.labels[name];
+      let start = start.evaluate(&self.labels, &[], address);
+      let end = end.evaluate(&self.labels, &[], address);
+      if start > end {
+        error!(pos, "Checksum range is backwards: 0x{:X}..0x{:X}", start, end);
+      }
+      if start < INST_OFFSET_BASE || end as usize > image_end {
+        error!(
+          pos,
+          "Checksum range 0x{:X}..0x{:X} is outside of the image",
+          start,
+          end,
+        );
+      }
+      if start <= address && address < end {
+        error!(pos, "A checksum can't cover itself");
+      }
+      let range =
+        (start - INST_OFFSET_BASE) as usize..(end - INST_OFFSET_BASE) as usize;
+      image[(address - INST_OFFSET_BASE) as usize] =
+        algorithm.compute(&image[range]);
+    }
+  }
+
+  // switch INDEX, DEFAULT, L0, L1, ...
+  //   mi sc0, N
+  //   jl INDEX, sc0, @switch
+  //   ji DEFAULT
+  // @switch:
+  //   mi sc0, @table
+  //   ad sc0, INDEX
+  //   md ip, sc0
+  // @table:
+  //   data L0, L1, ...
+  fn lower_switch(&mut self) {
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let mut args = match dir.var {
+        DirectiveVar::Op(ref op, ref args) if op == "switch" => args.clone(),
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      if args.len() < 3 {
+        error!(
+          dir.pos,
+          "switch needs an index, a default, and at least one case",
+        );
+      }
+      let cases = args.split_off(2);
+      let default = args.pop().unwrap();
+      let index = args.pop().unwrap();
+      if let OpArgVar::Label(ref name) = index.var {
+        if name == "sc0" {
+          error!(index.pos, "sc0 can't be the index of a switch");
+        }
+      }
+
+      let in_range = self.hidden_label("switch");
+      let table = self.hidden_label("table");
+      let pos = dir.pos.clone();
+      let arg = |var| OpArg {
+        var: var,
+        pos: pos.clone(),
+      };
+      let mut push = |var| self.directives.push(Directive {
+        var: var,
+        pos: pos.clone(),
+      });
+      let op = |op: &str, args| DirectiveVar::Op(op.to_owned(), args);
+      push(op("mi", vec![
+        arg(OpArgVar::Number(REG_SC0)),
+        arg(OpArgVar::Number(cases.len() as u16)),
+      ]));
+      push(op("jl", vec![
+        index.clone(),
+        arg(OpArgVar::Number(REG_SC0)),
+        arg(OpArgVar::Label(in_range.clone())),
+      ]));
+      push(op("ji", vec![default]));
+      push(DirectiveVar::Label(in_range, Public::Private));
+      push(op("mi", vec![
+        arg(OpArgVar::Number(REG_SC0)),
+        arg(OpArgVar::Label(table.clone())),
+      ]));
+      push(op("ad", vec![arg(OpArgVar::Number(REG_SC0)), index]));
+      push(op("md", vec![
+        arg(OpArgVar::Number(REG_IP)),
+        arg(OpArgVar::Number(REG_SC0)),
+      ]));
+      push(DirectiveVar::Label(table, Public::Private));
+      push(DirectiveVar::Data(cases));
+    }
+  }
+
+  // hidden labels start with `@', which can't start an identifier, so they
+  // can't collide with the program's labels
+  fn hidden_label(&mut self, kind: &str) -> String {
+    let label = format!("@{}{}", kind, self.hidden_labels);
+    self.hidden_labels += 1;
+    label
+  }
+
+  // proc NAME
+  //   uses r00, r01
+  //   arg a, b
+  //   local c, d
+  //   ...
+  // endproc
+  //
+  // the callee-save registers in `uses` are pushed on entry, and popped
+  // before every ret; writing to any other callee-save register is an
+  // error.
+  //
+  // args are given the CYBERT argument slots, in order, starting at s00.
+  // locals get the lowest caller-save slot that isn't in use for their
+  // lifetime; a local's lifetime runs from its first mention to its last,
+  // and covers any loop it's mentioned in. locals also stay out of the slots
+  // the proc names itself, like the s00 in `mi s00, 5', and out of every
+  // slot a `call'ed proc uses. there's no knowing which slots a callm, or a
+  // call to anything but a proc, uses; so a local can't live across one.
+  fn lower_procs(&mut self) {
+    const SLOT_BASE: u16 = 0x40;
+    const SLOT_END: u16 = 0x100;
+    // (first, last) directive indices into the body
+    type Lifetime = (usize, usize);
+    struct Proc {
+      name: String,
+      pos: Position,
+      uses: Vec<String>,
+      args: Vec<String>,
+      locals: Vec<String>,
+      body: Vec<Directive>,
+    }
+    enum Alloc {
+      Pending,
+      Working,
+      // the slot of each arg and local, and every slot the proc or its
+      // callees may write
+      Done(Vec<(String, u16)>, Vec<u16>),
+    }
+
+    fn rename(arg: &mut OpArg, names: &HashMap<String, String>) {
+      match arg.var {
+        OpArgVar::Label(ref mut label) => if let Some(new) = names.get(label) {
+          *label = new.clone();
+        },
+        OpArgVar::ArithOp(_, ref mut lhs, ref mut rhs) => {
+          rename(lhs, names);
+          rename(rhs, names);
+        },
+        OpArgVar::Immediate(ref mut value)
+        | OpArgVar::Builtin(_, ref mut value) => rename(value, names),
+        OpArgVar::Indirect(ref mut base, ref mut offset) => {
+          rename(base, names);
+          rename(offset, names);
+        },
+        _ => {},
+      }
+    }
+    fn mentions(arg: &OpArg, name: &str) -> bool {
+      match arg.var {
+        OpArgVar::Label(ref label) => label == name,
+        OpArgVar::ArithOp(_, ref lhs, ref rhs) =>
+          mentions(lhs, name) || mentions(rhs, name),
+        OpArgVar::Immediate(ref value)
+        | OpArgVar::Builtin(_, ref value) => mentions(value, name),
+        OpArgVar::Indirect(ref base, ref offset) =>
+          mentions(base, name) || mentions(offset, name),
+        _ => false,
+      }
+    }
+    fn args_of(dir: &Directive) -> &[OpArg] {
+      match dir.var {
+        DirectiveVar::Op(_, ref args) | DirectiveVar::Data(ref args) => args,
+        DirectiveVar::Const(_, ref arg, _) => ::std::slice::from_ref(arg),
+        _ => &[],
+      }
+    }
+    fn names(pos: &Position, args: &[OpArg]) -> Vec<String> {
+      args.iter().map(|arg| match arg.var {
+        OpArgVar::Label(ref name) => name.clone(),
+        _ => error!(pos, "Expected a name"),
+      }).collect()
+    }
+
+    // the slots the proc names itself, as addresses rather than values
+    fn named_slots(
+      this: &Parser, proc_: &Proc, constants: &HashMap<String, u16>,
+    ) -> Vec<u16> {
+      fn slot(arg: &OpArg, constants: &HashMap<String, u16>) -> Option<u16> {
+        let arg = match arg.var {
+          OpArgVar::Indirect(ref base, _) => &**base,
+          _ => arg,
+        };
+        arg.try_evaluate(constants)
+          .filter(|slot| (SLOT_BASE..SLOT_END).contains(slot))
+      }
+      let mut slots = Vec::new();
+      for dir in &proc_.body {
+        let (op, args) = match dir.var {
+          DirectiveVar::Op(ref op, ref args) => (op, args),
+          _ => continue,
+        };
+        for (arg, usage) in args.iter().zip(this.arg_usage(&dir.pos, op)) {
+          let is_var = proc_.args.iter().chain(&proc_.locals)
+            .any(|var| mentions(arg, var));
+          if (usage.read || usage.write) && !is_var {
+            slots.extend(slot(arg, constants));
+          }
+        }
+      }
+      slots
+    }
+    // gives the proc's args and locals their slots, once its callees have
+    // theirs; returns every slot the proc, or anything it calls, may write
+    fn allocate(
+      this: &Parser,
+      idx: usize,
+      procs: &[Proc],
+      allocs: &mut [Alloc],
+      constants: &HashMap<String, u16>,
+    ) -> Vec<u16> {
+      match allocs[idx] {
+        Alloc::Done(_, ref clobbered) => return clobbered.clone(),
+        // a recursive call; the slots are already being avoided
+        Alloc::Working => return Vec::new(),
+        Alloc::Pending => {},
+      }
+      allocs[idx] = Alloc::Working;
+      let proc_ = &procs[idx];
+      let body = &proc_.body;
+
+      let mut avoid = named_slots(this, proc_, constants);
+      // the indices of the calls which can't be followed
+      let mut unknown_calls = Vec::new();
+      for (i, dir) in body.iter().enumerate() {
+        let (op, args) = match dir.var {
+          DirectiveVar::Op(ref op, ref args) => (op, args),
+          _ => continue,
+        };
+        let callee = match (&**op, args.as_slice()) {
+          ("call", [OpArg { var: OpArgVar::Label(callee), .. }]) =>
+            procs.iter().position(|p| p.name == *callee),
+          ("call", _) | ("callm", _) => None,
+          _ => continue,
+        };
+        match callee {
+          Some(callee) =>
+            avoid.extend(allocate(this, callee, procs, allocs, constants)),
+          None => unknown_calls.push(i),
+        }
+      }
+
+      let lifetime = |var: &str, from: Option<usize>| {
+        let mut uses = body.iter().enumerate()
+          .filter(|&(_, d)| args_of(d).iter().any(|a| mentions(a, var)))
+          .map(|(i, _)| i);
+        let first = uses.next();
+        let last = uses.next_back().or(first);
+        match (from, first, last) {
+          (Some(from), _, Some(last)) => Some((from, last)),
+          (None, Some(first), Some(last)) => Some((first, last)),
+          _ => None,
+        }
+      };
+      // loops, as (label, jump) indices
+      let mut loops = Vec::new();
+      for (i, label) in body.iter().enumerate() {
+        if let DirectiveVar::Label(ref label, _) = label.var {
+          for (j, jump) in body.iter().enumerate().skip(i) {
+            if args_of(jump).iter().any(|a| mentions(a, label)) {
+              loops.push((i, j));
+            }
+          }
+        }
+      }
+      let extend = |mut life: Lifetime| {
+        loop {
+          let old = life;
+          for &(start, end) in &loops {
+            if life.0 <= end && start <= life.1 {
+              life = (life.0.min(start), life.1.max(end));
+            }
+          }
+          if life == old {
+            return life;
+          }
+        }
+      };
+
+      let mut slots: Vec<(String, u16, Option<Lifetime>)> = Vec::new();
+      for (i, arg) in proc_.args.iter().enumerate() {
+        let slot = SLOT_BASE + i as u16;
+        if slot >= SLOT_END {
+          error!(proc_.pos, "Too many args for proc {}", proc_.name);
+        }
+        // args are live from the start of the proc
+        let life = lifetime(arg, Some(0)).map(&extend);
+        slots.push((arg.clone(), slot, life));
+      }
+      let mut order = proc_.locals.iter()
+        .map(|local| (local, lifetime(local, None).map(&extend)))
+        .collect::<Vec<_>>();
+      order.sort_by_key(|&(_, life)| life);
+      for (local, life) in order {
+        let across = unknown_calls.iter().find(|&&call| match life {
+          Some(life) => life.0 <= call && call < life.1,
+          None => false,
+        });
+        if let Some(&call) = across {
+          error!(
+            body[call].pos,
+            "{}.{} is live across a call which isn't to a proc, so its slot \
+             may be overwritten",
+            proc_.name,
+            local,
+          );
+        }
+        let overlaps = |other: &Option<Lifetime>| match (life, *other) {
+          (Some(a), Some(b)) => a.0 <= b.1 && b.0 <= a.1,
+          _ => false,
+        };
+        let slot = (SLOT_BASE..SLOT_END).find(|&slot| {
+          !avoid.contains(&slot)
+            && !slots.iter().any(|s| s.1 == slot && overlaps(&s.2))
+        });
+        match slot {
+          Some(slot) => slots.push((local.clone(), slot, life)),
+          None => error!(
+            proc_.pos, "Ran out of scratch slots in proc {}", proc_.name,
+          ),
+        }
+      }
+
+      let mut clobbered = avoid;
+      clobbered.extend(slots.iter().map(|s| s.1));
+      let slots = slots.into_iter().map(|(var, slot, _)| (var, slot)).collect();
+      allocs[idx] = Alloc::Done(slots, clobbered.clone());
+      clobbered
+    }
+
+    // the directives outside of procs, with None where each proc was
+    let mut outside = Vec::new();
+    let mut procs = Vec::new();
+    let directives = ::std::mem::take(&mut self.directives);
+    let mut iter = directives.into_iter();
+    while let Some(dir) = iter.next() {
+      let name = match dir.var {
+        DirectiveVar::Op(ref op, ref args) if op == "proc" => {
+          match names(&dir.pos, args).as_slice() {
+            [name] => name.clone(),
+            _ => error!(dir.pos, "Expected a single name for proc"),
+          }
+        },
+        DirectiveVar::Op(ref op, _)
+        if op == "endproc" || op == "arg" || op == "local" || op == "uses" =>
+          error!(dir.pos, "{} outside of a proc", op),
+        _ => {
+          outside.push(Some(dir));
+          continue;
+        },
+      };
+
+      let mut uses = Vec::new();
+      let mut args = Vec::new();
+      let mut locals = Vec::new();
+      let mut body = Vec::new();
+      loop {
+        let inner = match iter.next() {
+          Some(inner) => inner,
+          None => error!(dir.pos, "Unterminated proc: {}", name),
+        };
+        match inner.var {
+          DirectiveVar::Op(ref op, _) if op == "endproc" => break,
+          DirectiveVar::Op(ref op, _) if op == "proc" =>
+            error!(inner.pos, "Procs can't be nested"),
+          DirectiveVar::Op(ref op, ref names_) if op == "arg" =>
+            args.extend(names(&inner.pos, names_)),
+          DirectiveVar::Op(ref op, ref names_) if op == "local" =>
+            locals.extend(names(&inner.pos, names_)),
+          DirectiveVar::Op(ref op, ref names_) if op == "uses" => {
+            for reg in names(&inner.pos, names_) {
+              if !self.is_callee_save(&reg) {
+                error!(inner.pos, "Not a callee-save register: {}", reg);
+              }
+              uses.push(reg);
+            }
+          },
+          _ => body.push(inner),
+        }
+      }
+      procs.push(Proc {
+        name: name,
+        pos: dir.pos,
+        uses: uses,
+        args: args,
+        locals: locals,
+        body: body,
+      });
+      outside.push(None);
+    }
+
+    let constants = self.early_constants();
+    let mut allocs = procs.iter().map(|_| Alloc::Pending).collect::<Vec<_>>();
+    for idx in 0..procs.len() {
+      allocate(self, idx, &procs, &mut allocs, &constants);
+    }
+
+    let mut procs = procs.into_iter().zip(allocs);
+    for dir in outside {
+      let (proc_, alloc) = match dir {
+        Some(dir) => {
+          self.directives.push(dir);
+          continue;
+        },
+        None => procs.next().unwrap(),
+      };
+      let Proc { name, pos, uses, body, .. } = proc_;
+      let slots = match alloc {
+        Alloc::Done(slots, _) => slots,
+        _ => unreachable!("ICE: a proc wasn't allocated"),
+      };
+
+      let mut renames = HashMap::new();
+      for (var, slot) in slots {
+        if renames.contains_key(&var) {
+          error!(pos, "Variable declared twice in proc {}: {}", name, var);
+        }
+        let label = format!("{}.{}", name, var);
+        if self.labels.insert(label.clone(), slot).is_some() {
+          error!(pos, "Attempted to redefine label: {}", label);
+        }
+        self.listed_constants.push(label.clone());
+        renames.insert(var, label);
+      }
+
+      for inner in &body {
+        if let DirectiveVar::Op(ref op, ref args) = inner.var {
+          for reg in self.written_args(&inner.pos, op, args) {
+            if self.is_callee_save(reg) && !uses.iter().any(|r| r == reg) {
+              error!(
+                inner.pos,
+                "proc {} writes to {} without declaring it in `uses'",
+                name,
+                reg,
+              );
+            }
+          }
+        }
+      }
+
+      let save = |op: &str, reg: &str, pos: &Position| Directive {
+        var: DirectiveVar::Op(op.to_owned(), vec![OpArg {
+          var: OpArgVar::Label(reg.to_owned()),
+          pos: pos.clone(),
+        }]),
+        pos: pos.clone(),
+      };
+      self.directives.push(Directive {
+        var: DirectiveVar::Label(name, Public::Private),
+        pos: pos.clone(),
+      });
+      for reg in &uses {
+        self.directives.push(save("push", reg, &pos));
+      }
+      for mut inner in body {
+        if let DirectiveVar::Op(ref op, _) = inner.var {
+          if op == "ret" {
+            for reg in uses.iter().rev() {
+              self.directives.push(save("pop", reg, &inner.pos));
+            }
+          }
+        }
+        match inner.var {
+          DirectiveVar::Op(_, ref mut args) | DirectiveVar::Data(ref mut args) =>
+            for arg in args {
+              rename(arg, &renames);
+            },
+          DirectiveVar::Const(_, ref mut arg, _) => rename(arg, &renames),
+          _ => {},
+        }
+        self.directives.push(inner);
+      }
+    }
+  }
+
+  fn is_callee_save(&self, name: &str) -> bool {
+    name.starts_with('r') && match self.labels.get(name) {
+      Some(&reg) => (REG_CALLEE_SAVE..REG_CALLEE_SAVE_END).contains(&reg),
+      None => false,
+    }
+  }
+
+  // the names of the arguments which an op writes to
+  fn written_args<'a>(
+    &self, pos: &Position, op: &str, args: &'a [OpArg],
+  ) -> Vec<&'a str> {
+    let usage = self.arg_usage(pos, op);
+    args.iter().zip(usage).filter_map(|(arg, usage)| match arg.var {
+      OpArgVar::Label(ref name) if usage.write => Some(&**name),
+      _ => None,
+    }).collect()
+  }
+
+  // how each of an op's arguments is used by its expansion
+  fn arg_usage(&self, pos: &Position, op: &str) -> Vec<ArgUsage> {
+    fn immediate(arg: &OpArg, usage: &mut [ArgUsage]) {
+      match arg.var {
+        OpArgVar::MacroArg(n) => usage[n as usize].immediate = true,
+        OpArgVar::ArithOp(_, ref lhs, ref rhs) => {
+          immediate(lhs, usage);
+          immediate(rhs, usage);
+        },
+        OpArgVar::Immediate(ref value) => immediate(value, usage),
+        _ => {},
+      }
+    }
+
+    let &(n_args, ref ops) = match self.macros.get(op) {
+      Some(mac) => mac,
+      None => error!(pos, "Unknown opcode: {}", op),
+    };
+    let mut usage = vec![ArgUsage::default(); n_args as usize];
+    for &(base, ref base_args) in ops {
+      for (i, arg) in base_args.iter().enumerate() {
+        let n = match arg.var {
+          OpArgVar::MacroArg(n) => n as usize,
+          _ => {
+            immediate(arg, &mut usage);
+            continue;
+          },
+        };
+        match (base, i) {
+          (BaseOp::MoveImmediate, 1) | (_, 2) => usage[n].immediate = true,
+          (BaseOp::MoveImmediate, 0)
+          | (BaseOp::Move, 0)
+          | (BaseOp::MoveDeref, 0) => usage[n].write = true,
+          // these write through their operands, not to them
+          (BaseOp::Load, _) | (BaseOp::Store, _) | (BaseOp::JumpGreater, _)
+          | (BaseOp::JumpLesser, _) | (BaseOp::JumpEqual, _) =>
+            usage[n].read = true,
+          (_, 0) => {
+            usage[n].read = true;
+            usage[n].write = true;
+          },
+          (_, _) => usage[n].read = true,
+        }
+      }
+    }
+    usage
+  }
+
+  // an instruction may have one `[base + offset]' operand. its address is
+  // computed into sc2, and the instruction operates on sc3, which is loaded
+  // from and stored back to that address as needed:
+  //   mv sc2, base
+  //   mi sc3, offset
+  //   ad sc2, sc3
+  //   md sc3, sc2
+  //   op sc3, ...
+  //   st sc3, sc2
+  fn lower_indirect(&mut self) {
+    fn is_indirect(arg: &OpArg) -> bool {
+      matches!(arg.var, OpArgVar::Indirect(..))
+    }
+
+    let directives = ::std::mem::take(&mut self.directives);
+    for dir in directives {
+      let (op, mut args) = match dir.var {
+        DirectiveVar::Op(ref op, ref args) if args.iter().any(is_indirect) =>
+          (op.clone(), args.clone()),
+        _ => {
+          self.directives.push(dir);
+          continue;
+        },
+      };
+      let mut indirect = args.iter().enumerate().filter(|&(_, a)| is_indirect(a));
+      let n = indirect.next().unwrap().0;
+      if let Some((_, arg)) = indirect.next() {
+        error!(arg.pos, "Only one indirect operand is allowed per instruction");
+      }
+      for arg in &args {
+        if let OpArgVar::Label(ref name) = arg.var {
+          if name == "sc2" || name == "sc3" {
+            error!(
+              arg.pos,
+              "{} can't be used alongside an indirect operand",
+              name,
+            );
+          }
+        }
+      }
+      let usage = self.arg_usage(&dir.pos, &op);
+      if usage.len() != args.len() {
+        error!(
+          dir.pos,
+          "Invalid number of args to {}; expected {}, found {}",
+          op,
+          usage.len(),
+          args.len(),
+        );
+      }
+      let usage = usage[n];
+      if usage.immediate {
+        error!(args[n].pos, "Indirect operands must be memory operands");
+      }
+
+      let pos = args[n].pos.clone();
+      let (base, offset) = match ::std::mem::replace(
+        &mut args[n].var,
+        OpArgVar::Number(REG_SC3),
+      ) {
+        OpArgVar::Indirect(base, offset) => (*base, *offset),
+        _ => unreachable!(),
+      };
+      let reg = |reg| OpArg {
+        var: OpArgVar::Number(reg),
+        pos: pos.clone(),
+      };
+      let mut push = |op: &str, args: Vec<OpArg>| {
+        self.directives.push(Directive {
+          var: DirectiveVar::Op(op.to_owned(), args),
+          pos: dir.pos.clone(),
+        });
+      };
+      push("mv", vec![reg(REG_SC2), base]);
+      match offset.var {
+        OpArgVar::Number(0) => {},
+        _ => {
+          push("mi", vec![reg(REG_SC3), offset]);
+          push("ad", vec![reg(REG_SC2), reg(REG_SC3)]);
+        },
+      }
+      if usage.read {
+        push("md", vec![reg(REG_SC3), reg(REG_SC2)]);
+      }
+      push(&op, args);
+      if usage.write {
+        push("st", vec![reg(REG_SC3), reg(REG_SC2)]);
+      }
+    }
+  }
+
+  // every distinct immediate operand gets a word in the constant pool, which
+  // is placed at the end of the program
+  fn build_const_pool(&mut self, end: u16) {
+    fn collect(
+      this: &Parser,
+      arg: &OpArg,
+      mac_args: &[OpArg],
+      inst_offset: u16,
+      pool: &mut Vec<u16>,
+    ) {
+      match arg.var {
+        OpArgVar::Immediate(ref value) => {
+          // a macro which takes this operand from the pool already, like
+          // adi with --pool-macros
+          if let OpArgVar::MacroArg(n) = value.var {
+            if let Some(arg) = mac_args.get(n as usize) {
+              if let OpArgVar::Immediate(_) = arg.var {
+                error!(
+                  arg.pos,
+                  "This operand comes from the constant pool already, so it \
+                   can't be a #immediate",
+                );
+              }
+            }
+          }
+          let value = value.evaluate(&this.labels, mac_args, inst_offset);
+          if !pool.contains(&value) {
+            pool.push(value);
+          }
+        },
+        // the argument count is checked when the op is emitted
+        OpArgVar::MacroArg(n) => if let Some(arg) = mac_args.get(n as usize) {
+          collect(this, arg, &[], inst_offset, pool);
+        },
+        _ => {},
+      }
+    }
+
+    let mut pool = Vec::new();
+    let mut inst_offset = INST_OFFSET_BASE;
+    for dir in &self.directives {
+      match dir.var {
+        DirectiveVar::Op(ref op, ref mac_args) => {
+          let (_, ref ops) = self.macros[op];
+          for (op, args) in ops {
+            for arg in args {
+              collect(self, arg, mac_args, inst_offset, &mut pool);
+            }
+            inst_offset += self.size_of_op(*op);
+          }
+        },
+        DirectiveVar::Data(ref data) => inst_offset += data.len() as u16,
+        DirectiveVar::Reserve(words) => inst_offset += words,
+        _ => {},
+      }
+    }
+
+    for (i, &value) in pool.iter().enumerate() {
+      self.labels.insert(lexer::const_pool_label(value), end + i as u16);
+    }
+    self.const_pool = pool;
+  }
+
+  fn size_of_op_str(&self, pos: &Position, op: &str) -> u16 {
+    match self.macros.get(op) {
+      Some((_, ops)) => {
+        let mut acc = 0;
+        for (op, _args) in ops {
+          acc += self.size_of_op(*op);
+        }
+        acc
+      }
+      None => error!(pos, "Unknown opcode: {}", op),
+    }
+  }
+
+  fn size_of_op(&self, op: BaseOp) -> u16 {
+    match op {
+      BaseOp::MoveImmediate | BaseOp::Move | BaseOp::MoveDeref | BaseOp::Load
+      | BaseOp::Store | BaseOp::Add | BaseOp::Sub | BaseOp::And | BaseOp::Or
+      | BaseOp::Xor | BaseOp::ShiftRight | BaseOp::ShiftLeft
+      | BaseOp::ShiftArithmetic => 2,
+      BaseOp::JumpGreater | BaseOp::JumpLesser | BaseOp::JumpEqual => 3,
+    }
+  }
+
+  fn next_directive(&mut self) -> Option<Directive> {
+    if self.idx < self.directives.len() {
+      let ret = ::std::mem::replace(&mut self.directives[self.idx], Directive {
+        var: DirectiveVar::Label(String::new(), Public::Private),
+        pos: Position::empty(),
+      });
+      self.idx += 1;
+      Some(ret)
+    } else {
+      None
+    }
+  }
+}
+
+impl Iterator for Parser {
+  type Item = Opcode;
 
   fn next(&mut self) -> Option<Opcode> {
     fn arith(
@@ -540,29 +3418,29 @@ impl Iterator for Parser {
     ) -> (Opcode, u16) {
       match *op {
         BaseOp::MoveImmediate =>
-          arith(this, OpcodeVariant::MoveImmediate, args, &mac_args),
-        BaseOp::Move => arith(this, OpcodeVariant::Move, args, &mac_args),
+          arith(this, OpcodeVariant::MoveImmediate, args, mac_args),
+        BaseOp::Move => arith(this, OpcodeVariant::Move, args, mac_args),
         BaseOp::MoveDeref =>
-          arith(this, OpcodeVariant::MoveDeref, args, &mac_args),
-        BaseOp::Load => arith(this, OpcodeVariant::Load, args, &mac_args),
-        BaseOp::Store => arith(this, OpcodeVariant::Store, args, &mac_args),
-        BaseOp::Add => arith(this, OpcodeVariant::Add, args, &mac_args),
-        BaseOp::Sub => arith(this, OpcodeVariant::Sub, args, &mac_args),
-        BaseOp::And => arith(this, OpcodeVariant::And, args, &mac_args),
-        BaseOp::Or => arith(this, OpcodeVariant::Or, args, &mac_args),
-        BaseOp::Xor => arith(this, OpcodeVariant::Xor, args, &mac_args),
+          arith(this, OpcodeVariant::MoveDeref, args, mac_args),
+        BaseOp::Load => arith(this, OpcodeVariant::Load, args, mac_args),
+        BaseOp::Store => arith(this, OpcodeVariant::Store, args, mac_args),
+        BaseOp::Add => arith(this, OpcodeVariant::Add, args, mac_args),
+        BaseOp::Sub => arith(this, OpcodeVariant::Sub, args, mac_args),
+        BaseOp::And => arith(this, OpcodeVariant::And, args, mac_args),
+        BaseOp::Or => arith(this, OpcodeVariant::Or, args, mac_args),
+        BaseOp::Xor => arith(this, OpcodeVariant::Xor, args, mac_args),
         BaseOp::ShiftRight =>
-          arith(this, OpcodeVariant::ShiftRight, args, &mac_args),
+          arith(this, OpcodeVariant::ShiftRight, args, mac_args),
         BaseOp::ShiftLeft =>
-          arith(this, OpcodeVariant::ShiftLeft, args, &mac_args),
+          arith(this, OpcodeVariant::ShiftLeft, args, mac_args),
         BaseOp::ShiftArithmetic =>
-          arith(this, OpcodeVariant::ShiftArithmetic, args, &mac_args),
+          arith(this, OpcodeVariant::ShiftArithmetic, args, mac_args),
         BaseOp::JumpGreater =>
-          jump(this, OpcodeVariant::JumpGreater, args, &mac_args),
+          jump(this, OpcodeVariant::JumpGreater, args, mac_args),
         BaseOp::JumpLesser =>
-          jump(this, OpcodeVariant::JumpLesser, args, &mac_args),
+          jump(this, OpcodeVariant::JumpLesser, args, mac_args),
         BaseOp::JumpEqual =>
-          jump(this, OpcodeVariant::JumpEqual, args, &mac_args),
+          jump(this, OpcodeVariant::JumpEqual, args, mac_args),
       }
     }
 
@@ -586,8 +3464,18 @@ impl Iterator for Parser {
     if let Some(dir) = self.next_directive() {
       match dir.var {
         DirectiveVar::Op(op, mac_args) => {
+          let start = self.inst_offset;
+          if op == "jgs" || op == "jls" {
+            for arg in mac_args.iter().take(2) {
+              match arg.try_evaluate(&self.labels) {
+                Some(REG_SC0) | Some(REG_SC1) =>
+                  error!(arg.pos, "{} can't compare sc0 or sc1", op),
+                _ => {},
+              }
+            }
+          }
           match self.macros.get(&op) {
-            Some(&(ref size, ref ops)) => {
+            Some((size, ops)) => {
               if (mac_args.len() as u16) != *size {
                 error!(
                   dir.pos,
@@ -597,7 +3485,7 @@ impl Iterator for Parser {
                   mac_args.len(),
                 )
               }
-              for &(ref op, ref args) in ops {
+              for (op, args) in ops {
                 let (op, offset) = opcode(self, op, args, &mac_args);
                 self.inst_offset += offset;
                 self.op_buffer.push(op);
@@ -605,6 +3493,12 @@ impl Iterator for Parser {
             },
             None => error!(dir.pos, "Unknown opcode"),
           }
+          self.lines.push(SourceLine {
+            start: start,
+            end: self.inst_offset,
+            pos: dir.pos,
+            op: op,
+          });
           if let Some(op) = self.op_buffer.get_mut(0) {
             self.op_buffer_idx = 1;
             return Some(::std::mem::replace(op, Opcode {
@@ -620,6 +3514,19 @@ impl Iterator for Parser {
           self.inst_offset += offset;
           Some(data)
         }
+        DirectiveVar::Reserve(words) => {
+          self.inst_offset += words;
+          // taken directives are left as labels, so this only sees what
+          // follows the reserve
+          if self.idx > self.initialized_end() {
+            return self.next();
+          }
+          Some(Opcode {
+            var: OpcodeVariant::Data(vec![0; words as usize]),
+            reg: 0,
+            num: 0,
+          })
+        }
         DirectiveVar::Label(..) | DirectiveVar::Const(..) => {
           while let Some(dir) = self.directives.get(self.idx) {
             match dir.var {
@@ -639,8 +3546,28 @@ impl Iterator for Parser {
           // imports aren't dealt with here
           self.next()
         },
+        // asserts are checked before anything is emitted
+        DirectiveVar::Assert(..) => self.next(),
         DirectiveVar::Macro{..} => unimplemented!(),
+        DirectiveVar::Struct(..) | DirectiveVar::StructData(..)
+        | DirectiveVar::Enum(..) | DirectiveVar::ReserveWords(..) =>
+          unreachable!("ICE: types should have been lowered"),
+        DirectiveVar::Checksum(..) =>
+          unreachable!("ICE: checksums should have been lowered"),
+        DirectiveVar::Random(..) | DirectiveVar::Seed(..) =>
+          unreachable!("ICE: random data should have been lowered"),
+        DirectiveVar::Diagnostic(..) =>
+          unreachable!("ICE: diagnostics should have been reported"),
       }
+    } else if !self.const_pool.is_empty() {
+      let pool = ::std::mem::take(&mut self.const_pool);
+      let len = pool.len() as u16;
+      self.inst_offset += len;
+      Some(Opcode {
+        var: OpcodeVariant::Data(pool),
+        reg: 0,
+        num: 0,
+      })
     } else {
       None
     }
@@ -0,0 +1,35 @@
+; linked in by the assembler for --encrypt; called by the startup stub with
+; the return address in __rt2, rather than through the stack. the key is
+; left in __rt3, so each region carries on where the last one stopped
+
+; void __decrypt(uint16_t* data, uint16_t* end) {
+;   while (data != end) {
+;     *data ^= key;
+;     key += 0x9E37;
+;     data++;
+;   }
+; }
+
+equ __a_decrypt_data __rt0
+equ __a_decrypt_end __rt1
+equ __a_decrypt_ret __rt2
+equ __a_decrypt_key __rt3
+
+equ __v_decrypt_word __rt4
+
+__decrypt:
+	; while (data != end) {
+	__l_decrypt_loop:
+	jq __a_decrypt_data, __a_decrypt_end, __l_decrypt_loop_end
+		; *data ^= key;
+		md __v_decrypt_word, __a_decrypt_data
+		xr __v_decrypt_word, __a_decrypt_key
+		st __v_decrypt_word, __a_decrypt_data
+		; key += 0x9E37;
+		adi __a_decrypt_key, 0x9E37
+		; data++;
+		inc __a_decrypt_data
+	; }
+		ji __l_decrypt_loop
+	__l_decrypt_loop_end:
+	jm __a_decrypt_ret
@@ -0,0 +1,91 @@
+; linked in by the assembler for --encrypt --cipher swizzle; like __decrypt,
+; but each word is also run back through football/swizzle.asm's
+; permutation of half-nibbles, the way solve.py's unswizzle does
+
+; void __decrypt_swizzle(uint16_t* data, uint16_t* end) {
+;   while (data != end) {
+;     uint16_t word = *data;
+;     uint16_t val = 0;
+;     val |= (word & 0x0003) << 14;
+;     val |= (word & 0xC000) >> 2;
+;     val |= (word & 0x0300) << 2;
+;     val |= (word & 0x3000) >> 4;
+;     val |= (word & 0x0C00) >> 4;
+;     val |= (word & 0x000C) << 2;
+;     val |= (word & 0x00C0) >> 4;
+;     val |= (word & 0x0030) >> 4;
+;     *data = val ^ key;
+;     key += 0x9E37;
+;     data++;
+;   }
+; }
+
+equ __a_decrypt_swizzle_data __rt0
+equ __a_decrypt_swizzle_end __rt1
+equ __a_decrypt_swizzle_ret __rt2
+equ __a_decrypt_swizzle_key __rt3
+
+equ __v_decrypt_swizzle_word __rt4
+equ __v_decrypt_swizzle_val __rt5
+equ __v_decrypt_swizzle_tmp __rt6
+
+__decrypt_swizzle:
+	; while (data != end) {
+	__l_decrypt_swizzle_loop:
+	jq __a_decrypt_swizzle_data, __a_decrypt_swizzle_end, \
+		__l_decrypt_swizzle_loop_end
+		; uint16_t word = *data;
+		md __v_decrypt_swizzle_word, __a_decrypt_swizzle_data
+		; uint16_t val = 0;
+		mi __v_decrypt_swizzle_val, 0
+		; val |= (word & 0x0003) << 14;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x0003
+		sli __v_decrypt_swizzle_tmp, 14
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0xC000) >> 2;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0xC000
+		sri __v_decrypt_swizzle_tmp, 2
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x0300) << 2;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x0300
+		sli __v_decrypt_swizzle_tmp, 2
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x3000) >> 4;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x3000
+		sri __v_decrypt_swizzle_tmp, 4
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x0C00) >> 4;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x0C00
+		sri __v_decrypt_swizzle_tmp, 4
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x000C) << 2;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x000C
+		sli __v_decrypt_swizzle_tmp, 2
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x00C0) >> 4;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x00C0
+		sri __v_decrypt_swizzle_tmp, 4
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; val |= (word & 0x0030) >> 4;
+		mv __v_decrypt_swizzle_tmp, __v_decrypt_swizzle_word
+		ndi __v_decrypt_swizzle_tmp, 0x0030
+		sri __v_decrypt_swizzle_tmp, 4
+		or __v_decrypt_swizzle_val, __v_decrypt_swizzle_tmp
+		; *data = val ^ key;
+		xr __v_decrypt_swizzle_val, __a_decrypt_swizzle_key
+		st __v_decrypt_swizzle_val, __a_decrypt_swizzle_data
+		; key += 0x9E37;
+		adi __a_decrypt_swizzle_key, 0x9E37
+		; data++;
+		inc __a_decrypt_swizzle_data
+	; }
+		ji __l_decrypt_swizzle_loop
+	__l_decrypt_swizzle_loop_end:
+	jm __a_decrypt_swizzle_ret
@@ -0,0 +1,48 @@
+; linked in by the assembler for `divs' and `mods'; called with the return
+; address in __rt2, rather than through the stack. the quotient is rounded
+; towards zero, and the remainder has the sign of the dividend
+
+; (int16_t, int16_t) __divs(int16_t a, int16_t b) {
+;   int16_t a_sign = a >> 15;
+;   int16_t b_sign = b >> 15;
+;   (uint16_t quot, uint16_t rem) = __divu(abs(a), abs(b));
+;   int16_t quot_sign = a_sign ^ b_sign;
+;   return ((quot ^ quot_sign) - quot_sign, (rem ^ a_sign) - a_sign);
+; }
+
+equ __a_divs_a __rt0
+equ __a_divs_b __rt1
+equ __a_divs_ret __rt2
+
+equ __r_divs_quot __rt0
+equ __r_divs_rem __rt1
+
+; __divu doesn't touch these
+equ __v_divs_ret __rt8
+equ __v_divs_a_sign sc2
+equ __v_divs_quot_sign sc3
+
+__divs:
+	mv __v_divs_ret, __a_divs_ret
+	; int16_t a_sign = a >> 15;
+	mi sc0, 15
+	mv __v_divs_a_sign, __a_divs_a
+	sa __v_divs_a_sign, sc0
+	; int16_t b_sign = b >> 15;
+	mv __v_divs_quot_sign, __a_divs_b
+	sa __v_divs_quot_sign, sc0
+	; abs(a), abs(b)
+	xr __a_divs_a, __v_divs_a_sign
+	sb __a_divs_a, __v_divs_a_sign
+	xr __a_divs_b, __v_divs_quot_sign
+	sb __a_divs_b, __v_divs_quot_sign
+	; int16_t quot_sign = a_sign ^ b_sign;
+	xr __v_divs_quot_sign, __v_divs_a_sign
+	mi __a_divs_ret, __l_divs_divided
+	ji __divu
+	__l_divs_divided:
+	xr __r_divs_quot, __v_divs_quot_sign
+	sb __r_divs_quot, __v_divs_quot_sign
+	xr __r_divs_rem, __v_divs_a_sign
+	sb __r_divs_rem, __v_divs_a_sign
+	jm __v_divs_ret
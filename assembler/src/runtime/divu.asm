@@ -0,0 +1,77 @@
+; linked in by the assembler for `divu' and `modu', and used by `__divs';
+; called with the return address in __rt2, rather than through the stack.
+; dividing by zero gives a quotient of 0xFFFF, and leaves the remainder as
+; the dividend
+
+; (uint16_t, uint16_t) __divu(uint16_t a, uint16_t b) {
+;   uint16_t quot = 0;
+;   uint16_t rem = 0;
+;   for (uint16_t i = 16; i != 0; --i) {
+;     // rem may not fit in 16 bits after this shift; if so, it's certainly
+;     // greater than b
+;     uint16_t carry = rem >> 15;
+;     rem = (rem << 1) | (a >> 15);
+;     a <<= 1;
+;     quot <<= 1;
+;     if (carry || rem >= b) {
+;       rem -= b;
+;       quot |= 1;
+;     }
+;   }
+;   return (quot, rem);
+; }
+
+equ __a_divu_a __rt0
+equ __a_divu_b __rt1
+equ __a_divu_ret __rt2
+
+equ __r_divu_quot __rt0
+equ __r_divu_rem __rt1
+
+; __divs keeps its return address in __rt8
+equ __c_divu_1 __rt6
+equ __c_divu_15 __rt7
+
+equ __v_divu_quot __rt3
+equ __v_divu_rem __rt4
+equ __v_divu_i __rt5
+equ __v_divu_carry sc0
+equ __v_divu_tmp sc1
+
+__divu:
+	mi __c_divu_1, 1
+	mi __c_divu_15, 15
+	mi __v_divu_quot, 0
+	mi __v_divu_rem, 0
+	mi __v_divu_i, 16
+	; for (uint16_t i = 16; i != 0; --i) {
+	__l_divu_loop:
+		; uint16_t carry = rem >> 15;
+		mv __v_divu_carry, __v_divu_rem
+		sr __v_divu_carry, __c_divu_15
+		; rem = (rem << 1) | (a >> 15);
+		sl __v_divu_rem, __c_divu_1
+		mv __v_divu_tmp, __a_divu_a
+		sr __v_divu_tmp, __c_divu_15
+		or __v_divu_rem, __v_divu_tmp
+		; a <<= 1;
+		sl __a_divu_a, __c_divu_1
+		; quot <<= 1;
+		sl __v_divu_quot, __c_divu_1
+		; if (carry || rem >= b) {
+		jq __v_divu_carry, __c_divu_1, __l_divu_sub
+		jl __v_divu_rem, __a_divu_b, __l_divu_next
+		__l_divu_sub:
+			; rem -= b;
+			sb __v_divu_rem, __a_divu_b
+			; quot |= 1;
+			or __v_divu_quot, __c_divu_1
+		; }
+		__l_divu_next:
+		sb __v_divu_i, __c_divu_1
+	; }
+		mi __v_divu_tmp, 0
+		jg __v_divu_i, __v_divu_tmp, __l_divu_loop
+	mv __r_divu_quot, __v_divu_quot
+	mv __r_divu_rem, __v_divu_rem
+	jm __a_divu_ret
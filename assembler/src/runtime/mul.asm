@@ -0,0 +1,51 @@
+; linked in by the assembler for `mul'; called with the return address in
+; __rt2, rather than through the stack
+
+; uint16_t __mul(uint16_t a, uint16_t b) {
+;   uint16_t product = 0;
+;   while (b != 0) {
+;     if (b & 1) {
+;       product += a;
+;     }
+;     a <<= 1;
+;     b >>= 1;
+;   }
+;   return product;
+; }
+
+equ __a_mul_a __rt0
+equ __a_mul_b __rt1
+equ __a_mul_ret __rt2
+
+equ __r_mul_product __rt0
+
+equ __c_mul_0 __rt4
+equ __c_mul_1 __rt5
+
+equ __v_mul_product __rt3
+equ __v_mul_tmp __rt6
+
+__mul:
+	mi __c_mul_0, 0
+	mi __c_mul_1, 1
+	mi __v_mul_product, 0
+	; while (b != 0) {
+	__l_mul_loop:
+	jq __a_mul_b, __c_mul_0, __l_mul_loop_end
+		; if (b & 1) {
+		mv __v_mul_tmp, __a_mul_b
+		nd __v_mul_tmp, __c_mul_1
+		jq __v_mul_tmp, __c_mul_0, __l_mul_skip
+			; product += a;
+			ad __v_mul_product, __a_mul_a
+		; }
+		__l_mul_skip:
+		; a <<= 1;
+		sl __a_mul_a, __c_mul_1
+		; b >>= 1;
+		sr __a_mul_b, __c_mul_1
+	; }
+		ji __l_mul_loop
+	__l_mul_loop_end:
+	mv __r_mul_product, __v_mul_product
+	jm __a_mul_ret
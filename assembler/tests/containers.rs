@@ -0,0 +1,189 @@
+// writes executables and ELF files, and checks that reading them gives back
+// what was written, and that what's read runs
+
+extern crate assembler;
+
+use std::io;
+use std::path::Path;
+
+use assembler::container::{Contents, Executable, Segment};
+use assembler::elf;
+use assembler::emulator::Machine;
+use assembler::macros::catch_errors;
+use assembler::parser::{self, Options, SymbolKind};
+use assembler::Program;
+
+// (address, the words, or how many zeros)
+fn segments(exe: &Executable) -> Vec<(u16, Vec<u16>, u16)> {
+  exe.segments.iter().map(|segment| match segment.contents {
+    Contents::Data(ref words) => (segment.address, words.clone(), 0),
+    Contents::Zero(words) => (segment.address, Vec::new(), words),
+  }).collect()
+}
+
+fn sorted(symbols: &[(String, u16)]) -> Vec<(String, u16)> {
+  let mut symbols = symbols.to_vec();
+  symbols.sort();
+  symbols
+}
+
+// (name, value, section index) of each symbol in an ELF file
+fn elf_symbols(bytes: &[u8]) -> Vec<(String, u32, u16)> {
+  let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+  let u32_at = |at: usize| u32::from_le_bytes([
+    bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3],
+  ]);
+  let shoff = u32_at(32) as usize;
+  let section = |idx: usize| shoff + idx * u16_at(46) as usize;
+  let symtab = (0..u16_at(48) as usize).map(section)
+    .find(|&header| u32_at(header + 4) == 2)
+    .unwrap();
+  let strtab = u32_at(section(u32_at(symtab + 24) as usize) + 16) as usize;
+  let (offset, size) = (u32_at(symtab + 16) as usize, u32_at(symtab + 20));
+  (1..size as usize / 16).map(|i| {
+    let symbol = offset + 16 * i;
+    let name = &bytes[strtab + u32_at(symbol) as usize..];
+    let name = name.split(|&b| b == 0).next().unwrap();
+    let name = String::from_utf8(name.to_vec()).unwrap();
+    (name, u32_at(symbol + 4), u16_at(symbol + 14))
+  }).collect()
+}
+
+#[test]
+fn executable_round_trips() {
+  let exe = Executable {
+    entry: 0x1002,
+    stack: 0x400,
+    segments: vec![
+      Segment {
+        address: 0x1000,
+        contents: Contents::Data(vec![0xF000, 0, 0x1234, 0xFFFF]),
+      },
+      Segment {
+        address: 0x1004,
+        contents: Contents::Zero(6),
+      },
+    ],
+    symbols: vec![("main".to_owned(), 0x1002), ("SIZE".to_owned(), 6)],
+  };
+  let read = match Executable::read(&exe.write()) {
+    Ok(read) => read,
+    Err(e) => panic!("{}", e),
+  };
+  assert_eq!(read.entry, exe.entry);
+  assert_eq!(read.stack, exe.stack);
+  assert_eq!(segments(&read), segments(&exe));
+  assert_eq!(read.symbols, exe.symbols);
+}
+
+#[test]
+fn elf_round_trips() {
+  let image = [0x1040, 0x0003, 0xF000, 0x0000, 0x00AA, 0x00BB];
+  let layout = elf::Layout {
+    base: 0x1000,
+    entry: 0x1002,
+    text_end: 0x1004,
+    bss: (0x1006, 3),
+  };
+  let symbols = [
+    ("main", 0x1002, true, SymbolKind::Label),
+    ("table", 0x1004, false, SymbolKind::Label),
+    ("buffer", 0x1006, true, SymbolKind::Label),
+    ("end", 0x1009, false, SymbolKind::Label),
+    ("COUNT", 0x40, false, SymbolKind::Equ),
+    // an equ is absolute, even when its value looks like an address
+    ("LIMIT", 0x1002, true, SymbolKind::Equ),
+  ];
+  let bytes = elf::write(&image, &layout, &symbols);
+  let mut sections = elf_symbols(&bytes);
+  sections.sort();
+  assert_eq!(sections, [
+    ("COUNT".to_owned(), 0x40, 0xFFF1),
+    ("LIMIT".to_owned(), 0x1002, 0xFFF1),
+    ("buffer".to_owned(), 0x200C, 3),
+    ("end".to_owned(), 0x2012, 3),
+    ("main".to_owned(), 0x2004, 1),
+    ("table".to_owned(), 0x2008, 2),
+  ]);
+  let read = match elf::read(&bytes, 0x300) {
+    Ok(read) => read,
+    Err(e) => panic!("{}", e),
+  };
+  assert_eq!(read.entry, 0x1002);
+  assert_eq!(read.stack, 0x300);
+  assert_eq!(segments(&read), [
+    (0x1000, image[..4].to_vec(), 0),
+    (0x1004, image[4..].to_vec(), 0),
+    (0x1006, Vec::new(), 3),
+  ]);
+  let expected = symbols.iter()
+    .map(|&(name, value, _, _)| (name.to_owned(), value))
+    .collect::<Vec<_>>();
+  assert_eq!(sorted(&read.symbols), sorted(&expected));
+}
+
+#[test]
+fn elf_images_run() {
+  // the header's entry is used, and the .bss is zeroed
+  let source = "\
+entry start
+other:
+  mi 0x80, 1
+  hf
+start:
+  mv 0x81, sp
+  mv 0x82, value
+  mv 0x83, buf
+  mi buf, 9
+  mv 0x84, buf
+  hf
+value: data 0x1234
+buf: res 2
+";
+  let options = Options { header_entry: true, ..Options::default() };
+  let path = Path::new("test.asm");
+  let program = match catch_errors(|| {
+    Program::from_source(path, source, &options)
+  }).0 {
+    Ok(program) => program,
+    Err(error) => panic!("{}", error.message),
+  };
+  let bytes = elf::write(&program.image, &elf::Layout {
+    base: parser::INST_OFFSET_BASE,
+    entry: program.parser.entry(),
+    text_end: program.parser.text_end(),
+    bss: program.parser.bss(),
+  }, &program.parser.symbol_table());
+  let exe = match elf::read(&bytes, 0x280) {
+    Ok(exe) => exe,
+    Err(e) => panic!("{}", e),
+  };
+  let mut machine = match Machine::from_executable(
+    &exe, Box::new(io::empty()), Box::new(io::sink()),
+  ) {
+    Ok(machine) => machine,
+    Err(e) => panic!("{}", e),
+  };
+  let mut steps = 0;
+  while machine.step() {
+    steps += 1;
+    assert!(steps < 1000, "the program didn't halt");
+  }
+  assert_eq!(&machine.memory[0x80..0x85], [0, 0x280, 0x1234, 0, 9]);
+}
+
+#[test]
+fn segments_must_fit_in_memory() {
+  let exe = Executable {
+    entry: 0xFFFE,
+    stack: 0x300,
+    segments: vec![Segment {
+      address: 0xFFFE,
+      contents: Contents::Zero(3),
+    }],
+    symbols: Vec::new(),
+  };
+  let machine =
+    Machine::from_executable(&exe, Box::new(io::empty()), Box::new(io::sink()));
+  assert!(machine.is_err());
+}
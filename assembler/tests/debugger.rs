@@ -0,0 +1,112 @@
+// the debugger's stepping, without its REPL
+
+extern crate assembler;
+
+use std::io;
+use std::path::Path;
+
+use assembler::debugger::{self, Run, Stop};
+use assembler::emulator::Machine;
+use assembler::macros::catch_errors;
+use assembler::parser::Options;
+use assembler::Program;
+
+const SOURCE: &str = "\
+main:
+  mi sp, 0x300
+  call double
+  mi 0x81, 1
+  hf
+double:
+  mi 0x80, 5
+  ad 0x80, 0x80
+  ret
+";
+
+fn program() -> Program {
+  let path = Path::new("test.asm");
+  match catch_errors(|| {
+    Program::from_source(path, SOURCE, &Options::default())
+  }).0 {
+    Ok(program) => program,
+    Err(error) => panic!("{}", error.message),
+  }
+}
+
+fn machine(program: &Program) -> Machine {
+  match Machine::new(
+    &program.image, 0x300, Box::new(io::empty()), Box::new(io::sink()),
+  ) {
+    Ok(machine) => machine,
+    Err(e) => panic!("{}", e),
+  }
+}
+
+// the line of source ip is on
+fn line(program: &Program, machine: &Machine) -> usize {
+  match debugger::line_index(program, machine.ip()) {
+    Some(idx) => program.parser.lines()[idx].pos.line,
+    None => panic!("ip is at 0x{:04X}, which isn't on a line", machine.ip()),
+  }
+}
+
+#[test]
+fn step_goes_into_calls() {
+  let program = program();
+  let mut machine = machine(&program);
+  assert_eq!(line(&program, &machine), 2);
+  for &expected in &[3, 7, 8, 9, 4] {
+    assert_eq!(
+      debugger::step(&program, &mut machine, &[], Run::Step), Stop::Done,
+    );
+    assert_eq!(line(&program, &machine), expected);
+  }
+}
+
+#[test]
+fn next_runs_over_calls() {
+  let program = program();
+  let mut machine = machine(&program);
+  for &expected in &[3, 4, 5] {
+    assert_eq!(
+      debugger::step(&program, &mut machine, &[], Run::Next), Stop::Done,
+    );
+    assert_eq!(line(&program, &machine), expected);
+  }
+  assert_eq!(machine.memory[0x80], 10);
+  assert_eq!(
+    debugger::step(&program, &mut machine, &[], Run::Next), Stop::Halted,
+  );
+  assert_eq!(machine.memory[0x81], 1);
+}
+
+#[test]
+fn finish_returns_to_the_caller() {
+  let program = program();
+  let mut machine = machine(&program);
+  debugger::step(&program, &mut machine, &[], Run::Step);
+  debugger::step(&program, &mut machine, &[], Run::Step);
+  assert_eq!(line(&program, &machine), 7);
+  assert_eq!(
+    debugger::step(&program, &mut machine, &[], Run::Finish), Stop::Done,
+  );
+  assert_eq!(line(&program, &machine), 4);
+  assert_eq!(machine.memory[0x80], 10);
+}
+
+#[test]
+fn breakpoints_stop_continue() {
+  let program = program();
+  let mut machine = machine(&program);
+  let breakpoints = [None, program.parser.label("double")];
+  assert_eq!(
+    debugger::step(&program, &mut machine, &breakpoints, Run::Continue),
+    Stop::Breakpoint(2),
+  );
+  assert_eq!(line(&program, &machine), 7);
+  // not again, from the breakpoint itself
+  assert_eq!(
+    debugger::step(&program, &mut machine, &breakpoints, Run::Continue),
+    Stop::Halted,
+  );
+}
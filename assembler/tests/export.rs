@@ -0,0 +1,123 @@
+// what --export writes, for each language
+
+extern crate assembler;
+
+use std::path::Path;
+
+use assembler::export::{self, Language};
+use assembler::macros::catch_errors;
+
+const SYMBOLS: &[(&str, u16)] = &[
+  ("main", 0x1000),
+  ("Point.x", 0x0001),
+  ("loop", 0x1004),
+  ("2nd", 0x0002),
+];
+
+fn write(language: Language, symbols: &[(&str, u16)], prefix: &str) -> String {
+  let output = match language {
+    Language::C => "game-exports.h",
+    Language::Rust => "exports.rs",
+    Language::Python => "exports.py",
+  };
+  match catch_errors(|| {
+    export::write(language, symbols, prefix, "game.asm", Path::new(output))
+  }).0 {
+    Ok(out) => out,
+    Err(error) => panic!("{}", error.message),
+  }
+}
+
+#[test]
+fn c_headers() {
+  assert_eq!(write(Language::C, SYMBOLS, ""), "\
+// generated by the CT64k assembler from game.asm; don't edit
+#ifndef GAME_EXPORTS_H
+#define GAME_EXPORTS_H
+
+#define main 0x1000
+#define Point_x 0x0001
+#define loop 0x1004
+#define _2nd 0x0002
+
+#endif // GAME_EXPORTS_H
+");
+  // keywords get a `_'
+  assert_eq!(
+    write(Language::C, &[("if", 1), ("struct", 2)], "").lines()
+      .filter(|line| line.starts_with("#define ") && line.contains(" 0x"))
+      .collect::<Vec<_>>(),
+    ["#define if_ 0x0001", "#define struct_ 0x0002"],
+  );
+}
+
+#[test]
+fn rust_modules() {
+  assert_eq!(write(Language::Rust, SYMBOLS, ""), "\
+// generated by the CT64k assembler from game.asm; don't edit
+#![allow(non_upper_case_globals)]
+
+pub const main: u16 = 0x1000;
+pub const Point_x: u16 = 0x0001;
+pub const r#loop: u16 = 0x1004;
+pub const _2nd: u16 = 0x0002;
+");
+  // these can't be raw identifiers
+  assert!(write(Language::Rust, &[("self", 1), ("_", 2)], "").ends_with("\
+pub const self_: u16 = 0x0001;
+pub const __: u16 = 0x0002;
+"));
+}
+
+#[test]
+fn python_modules() {
+  assert_eq!(write(Language::Python, SYMBOLS, ""), "\
+# generated by the CT64k assembler from game.asm; don't edit
+
+main = 0x1000
+Point_x = 0x0001
+loop = 0x1004
+_2nd = 0x0002
+");
+  assert!(write(Language::Python, &[("None", 1), ("lambda", 2)], "")
+    .ends_with("None_ = 0x0001\nlambda_ = 0x0002\n"));
+}
+
+#[test]
+fn prefixes() {
+  assert!(write(Language::C, SYMBOLS, "GAME_").contains("\
+#define GAME_main 0x1000
+#define GAME_Point_x 0x0001
+#define GAME_loop 0x1004
+#define GAME_2nd 0x0002
+"));
+  // a prefix can make a keyword into a plain name
+  assert!(write(Language::Rust, &[("op", 1)], "lo").ends_with("\
+pub const r#loop: u16 = 0x0001;
+"));
+}
+
+#[test]
+fn names_which_export_the_same_are_an_error() {
+  let (out, _) = catch_errors(|| export::write(
+    Language::C, &[("Point.x", 1), ("Point_x", 2)], "", "game.asm",
+    Path::new("exports.h"),
+  ));
+  match out {
+    Ok(_) => panic!("both names were exported"),
+    Err(error) => assert_eq!(
+      error.message,
+      "`Point.x' and `Point_x' would both be exported as `Point_x'",
+    ),
+  }
+}
+
+#[test]
+fn include_guards_come_from_the_output() {
+  let guard = |output: &str| {
+    let out = export::write(Language::C, &[], "", "game.asm", Path::new(output));
+    out.lines().nth(1).unwrap().to_owned()
+  };
+  assert_eq!(guard("include/ct64k.game.h"), "#ifndef CT64K_GAME_H");
+  assert_eq!(guard("/"), "#ifndef CT64K_EXPORTS_H");
+}
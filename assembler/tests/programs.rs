@@ -0,0 +1,852 @@
+// assembles small programs through the library, and checks what comes out
+
+extern crate assembler;
+
+use std::io;
+use std::path::Path;
+
+use assembler::emulator::Machine;
+use assembler::macros::{catch_errors, Level};
+use assembler::parser::{Cipher, Options, SymbolKind};
+use assembler::Program;
+
+fn assemble(source: &str) -> Program {
+  assemble_with(source, &Options::default())
+}
+
+fn assemble_with(source: &str, options: &Options) -> Program {
+  let path = Path::new("test.asm");
+  match catch_errors(|| Program::from_source(path, source, options)).0 {
+    Ok(program) => program,
+    Err(error) => panic!("{} at line {:?}", error.message, error.position),
+  }
+}
+
+// the message of the error which stops `source' assembling
+fn assemble_error(source: &str) -> String {
+  assemble_error_with(source, &Options::default())
+}
+
+fn assemble_error_with(source: &str, options: &Options) -> String {
+  let path = Path::new("test.asm");
+  match catch_errors(|| Program::from_source(path, source, options)).0 {
+    Ok(_) => panic!("expected an error"),
+    Err(error) => error.message,
+  }
+}
+
+// runs the program until it halts
+fn run(program: &Program) -> Machine {
+  let mut machine = match Machine::new(
+    &program.image, 0x300, Box::new(io::empty()), Box::new(io::sink()),
+  ) {
+    Ok(machine) => machine,
+    Err(e) => panic!("{}", e),
+  };
+  for _ in 0..100_000 {
+    if !machine.step() {
+      return machine;
+    }
+  }
+  panic!("the program didn't halt");
+}
+
+fn symbol(program: &Program, name: &str) -> u16 {
+  match program.parser.label(name) {
+    Some(value) => value,
+    None => panic!("no symbol {}", name),
+  }
+}
+
+// the `len' words of the image starting at `label'
+fn words<'a>(program: &'a Program, label: &str, len: usize) -> &'a [u16] {
+  let start = (symbol(program, label) - 0x1000) as usize;
+  &program.image[start..start + len]
+}
+
+fn symbol_names(program: &Program) -> Vec<&str> {
+  program.parser.symbols().into_iter().map(|(name, _)| name).collect()
+}
+
+#[test]
+fn hash_after_operandless_op_is_a_comment() {
+  let commented = assemble("main:\n  ret #done\n  hf #done\n");
+  let plain = assemble("main:\n  ret\n  hf\n");
+  assert_eq!(commented.image, plain.image);
+  // `leave' is a macro, and `endif' is lowered by a pass
+  let commented = assemble("main:\n  if sc1 == 0\n  leave #a\n  endif #b\n");
+  let plain = assemble("main:\n  if sc1 == 0\n  leave\n  endif\n");
+  assert_eq!(commented.image, plain.image);
+}
+
+#[test]
+fn pool_macro_operands_cant_be_immediates() {
+  let options = Options {
+    pool_macros: true,
+    ..Options::default()
+  };
+  assert_eq!(
+    assemble_error_with("main:\n  adi 0x40, #5\n  hf\n", &options),
+    "This operand comes from the constant pool already, so it can't be a \
+     #immediate",
+  );
+}
+
+#[test]
+fn pool_and_hidden_labels_are_not_symbols() {
+  let program = assemble("\
+main:
+  ad sc0, #7
+  if sc0 == sc1
+    hf
+  endif
+  switch sc1, main, main
+  hf
+");
+  assert_eq!(symbol_names(&program), ["main"]);
+}
+
+#[test]
+fn warnings_are_caught_along_with_errors() {
+  let source = "main:\n  warning \"careful\"\n  hf\n";
+  let (program, reported) = catch_errors(|| {
+    Program::from_source(Path::new("test.asm"), source, &Options::default())
+  });
+  assert!(program.is_ok());
+  assert_eq!(reported.len(), 1);
+  assert_eq!(reported[0].level, Level::Warning);
+  assert_eq!(reported[0].message, "careful");
+  assert_eq!(reported[0].position, Some(("test.asm".to_owned(), 2)));
+}
+
+#[test]
+fn locals_share_slots_when_their_lifetimes_dont_overlap() {
+  let program = assemble("\
+main:
+  hf
+proc f
+  arg a, b
+  local c, d
+  mv c, a
+  ad b, c
+  mi d, 1
+  ad b, d
+  ret
+endproc
+");
+  assert_eq!(symbol(&program, "f.a"), 0x40);
+  assert_eq!(symbol(&program, "f.b"), 0x41);
+  assert_eq!(symbol(&program, "f.c"), 0x42);
+  assert_eq!(symbol(&program, "f.d"), 0x40);
+}
+
+#[test]
+fn locals_avoid_named_and_callee_slots() {
+  let program = assemble("\
+equ s00 0x40
+equ s10 0x4A
+main:
+  mi sp, 0x300
+  call work
+  hf
+proc work
+  local keep
+  mi keep, 100
+  mi s00, 5
+  call double
+  ad s00, keep
+  mv s10, s00
+  ret
+endproc
+proc double
+  arg x
+  local t
+  mv t, x
+  ad x, t
+  ret
+endproc
+");
+  assert_eq!(symbol(&program, "work.keep"), 0x42);
+  assert_eq!(run(&program).memory[0x4A], 110);
+
+  // anything else could write any slot
+  let message = "work.keep is live across a call which isn't to a proc, so \
+                 its slot may be overwritten";
+  for call in &["call clob", "callm target"] {
+    let source = format!("\
+main:
+  hf
+clob:
+  mi 0x40, 0xDEAD
+  ret
+target: data clob
+proc work
+  local keep
+  mi keep, 5
+  {}
+  mv 0x80, keep
+  ret
+endproc
+", call);
+    assert_eq!(assemble_error(&source), message);
+  }
+  // a local which isn't live across the call is fine
+  assemble("\
+main:
+  hf
+clob:
+  ret
+proc work
+  local keep
+  mi keep, 5
+  mv 0x80, keep
+  call clob
+  ret
+endproc
+");
+}
+
+#[test]
+fn uses_saves_registers_on_every_path() {
+  let program = assemble("\
+equ s00 0x40
+main:
+  mi sp, 0x300
+  mi r00, 1
+  mi r01, 2
+  mi s00, 0
+  call pick
+  mv 0x80, r00
+  mv 0x81, r01
+  mi s00, 1
+  call pick
+  mv 0x82, r00
+  mv 0x83, r01
+  mv 0x84, sp
+  hf
+proc pick
+  uses r00, r01
+  arg x
+  mi r00, 7
+  mi r01, 8
+  jz x, zero
+  ret
+zero:
+  ret
+endproc
+");
+  let machine = run(&program);
+  assert_eq!(&machine.memory[0x80..0x85], [1, 2, 1, 2, 0x300]);
+
+  let message = "proc f writes to r02 without declaring it in `uses'";
+  assert_eq!(assemble_error("\
+main:
+  hf
+proc f
+  uses r00
+  mi r02, 5
+  ret
+endproc
+"), message);
+  // r48 to r51 are the argument slots, not callee-save registers
+  assert_eq!(assemble_error("\
+equ r48 0x40
+main:
+  hf
+proc f
+  uses r48
+  ret
+endproc
+"), "Not a callee-save register: r48");
+}
+
+#[test]
+fn data_keywords_are_names_outside_data() {
+  let program = assemble("\
+equ for 3
+main:
+  mv seed, rand
+  hf
+seed: data for
+rand: data 0
+seed 1
+table: data for i 3 i*2
+");
+  assert_eq!(symbol(&program, "for"), 3);
+  let table = (symbol(&program, "table") - 0x1000) as usize;
+  assert_eq!(program.image[table..table + 3], [0, 2, 4]);
+}
+
+#[test]
+fn seed_constant_seeds_random_data() {
+  let constant = assemble("equ SEED 40 + 2\nmain:\n  hf\ndata rand 4\n");
+  let directive = assemble("seed 42\nmain:\n  hf\ndata rand 4\n");
+  assert_eq!(constant.image, directive.image);
+}
+
+#[test]
+fn encrypt_region_can_end_the_program() {
+  let source = "\
+equ s00 0x40
+main:
+  mi s00, 0
+  ad s00, v1
+  hf
+encrypt
+v1: data 0x1234
+endencrypt
+";
+  for &cipher in &[Cipher::Xor, Cipher::Swizzle] {
+    let options = Options {
+      encrypt: Some((cipher, 1)),
+      ..Options::default()
+    };
+    let program = assemble_with(source, &options);
+    assert_eq!(run(&program).memory[0x40], 0x1234);
+  }
+}
+
+#[test]
+fn header_entry_replaces_the_entry_jump() {
+  let source = "entry start\nhelper:\n  ret\nstart:\n  hf\n";
+  let jumped = assemble(source);
+  let options = Options {
+    header_entry: true,
+    ..Options::default()
+  };
+  let headed = assemble_with(source, &options);
+  assert_eq!(jumped.parser.entry(), 0x1000);
+  assert_eq!(headed.parser.entry(), symbol(&headed, "start"));
+  assert_eq!(headed.image.len() + 2, jumped.image.len());
+}
+
+#[test]
+fn images_which_dont_fit_are_an_error() {
+  let machine = |words| Machine::new(
+    &vec![0; words], 0x300, Box::new(io::empty()), Box::new(io::sink()),
+  );
+  assert!(machine(0xF000).is_ok());
+  assert!(machine(0xF001).is_err());
+}
+
+#[test]
+fn string_escapes_and_utf8() {
+  let program = assemble("\
+main:
+  hf
+s: data \"a\\tb\\x41\\u{263A}é☺\\
+     z\", '\\n', 'é'
+");
+  assert_eq!(
+    words(&program, "s", 10),
+    [0x61, 0x09, 0x62, 0x41, 0x263A, 0xE9, 0x263A, 0x7A, 0x0A, 0xE9],
+  );
+}
+
+#[test]
+fn characters_above_u_ffff_are_an_error() {
+  let message = "Character U+1F600 does not fit in a 16-bit word";
+  assert_eq!(assemble_error("s: data \"\\u{1F600}\"\n"), message);
+  assert_eq!(assemble_error("s: data \"😀\"\n"), message);
+  assert_eq!(
+    assemble_error("s: data \"\\q\"\n"),
+    "Unrecognized escape sequence: \\q",
+  );
+}
+
+#[test]
+fn structs_and_their_initializers() {
+  let program = assemble("\
+struct Point
+  x:
+  y:
+  tag: 2 * SIZE
+endstruct
+equ SIZE 3
+main:
+  hf
+p: data Point { x: 1, y: -2, tag: 3, 4 }
+fields: data Point.x, Point.y, Point.tag, Point.size
+");
+  assert_eq!(words(&program, "p", 8), [1, 0xFFFE, 3, 4, 0, 0, 0, 0]);
+  assert_eq!(words(&program, "fields", 4), [0, 1, 2, 8]);
+  assert_eq!(symbol(&program, "fields"), symbol(&program, "p") + 8);
+}
+
+#[test]
+fn struct_sizes_cant_depend_on_labels() {
+  assert_eq!(
+    assemble_error("struct S\n  a: main\nendstruct\nmain:\n  hf\n"),
+    "Expected a constant which doesn't depend on any labels",
+  );
+}
+
+#[test]
+fn operand_expressions() {
+  let program = assemble("\
+main:
+  hf
+e: data (1 + 2) * 3, 7 / 2, 1 << 4, 0xF0 >> 4, 6 & 3, 6 | 3, 6 ^ 3, 2 - 3
+");
+  assert_eq!(words(&program, "e", 8), [9, 3, 0x10, 0xF, 2, 7, 5, 0xFFFF]);
+  assert_eq!(
+    assemble_error("main:\n  hf\ndata 1 / (2 - 2)\n"),
+    "Division by zero",
+  );
+}
+
+#[test]
+fn enums_and_flags() {
+  let program = assemble("\
+enum Color, 5, 2
+  Red
+  Green
+  Blue: 20
+  Cyan
+endenum
+flags Mode
+  Read
+  Write
+  Both: 3
+  Exec
+endflags
+main:
+  hf
+");
+  assert_eq!(symbol(&program, "Color.Red"), 5);
+  assert_eq!(symbol(&program, "Color.Green"), 7);
+  assert_eq!(symbol(&program, "Color.Blue"), 20);
+  assert_eq!(symbol(&program, "Color.Cyan"), 22);
+  assert_eq!(symbol(&program, "Mode.Read"), 1);
+  assert_eq!(symbol(&program, "Mode.Write"), 2);
+  assert_eq!(symbol(&program, "Mode.Both"), 3);
+  assert_eq!(symbol(&program, "Mode.Exec"), 4);
+}
+
+#[test]
+fn files_can_be_named_like_keywords() {
+  let dir = std::env::temp_dir().join("ct64k-import-flags");
+  std::fs::create_dir_all(&dir).unwrap();
+  std::fs::write(dir.join("flags.asm"), "equ IMPORTED 9\n").unwrap();
+  let source = "import flags\nmain:\n  hf\n";
+  let path = dir.join("main.asm");
+  let program = match catch_errors(|| {
+    Program::from_source(&path, source, &Options::default())
+  }).0 {
+    Ok(program) => program,
+    Err(error) => panic!("{}", error.message),
+  };
+  assert_eq!(symbol(&program, "IMPORTED"), 9);
+}
+
+#[test]
+fn frames_and_indirect_operands() {
+  // push 30; call f; and f's frame is
+  //   [bp-2] the argument, [bp-1] the return address, [bp] the caller's bp,
+  //   [bp+1] the local
+  let program = assemble("\
+main:
+  mi sp, 0x300
+  mi bp, 0x2F0
+  mi 0x90, 30
+  push 0x90
+  call f
+back:
+  mv 0x84, sp
+  mv 0x85, bp
+  hf
+f:
+  enter 1
+  mv 0x80, [bp-2]
+  mv 0x81, [bp-1]
+  mi [bp+1], 12
+  ad [bp+1], 0x80
+  mv 0x82, [bp+1]
+  mv 0x83, [bp]
+  leave
+  ret
+");
+  let machine = run(&program);
+  let back = symbol(&program, "back");
+  assert_eq!(&machine.memory[0x80..0x86], [30, back, 42, 0x2F0, 0x301, 0x2F0]);
+}
+
+#[test]
+fn register_memory_above_0fff_is_legalized() {
+  let source = "\
+main:
+  mi big, 5
+  ad big, big
+  mv 0x80, big
+  hf
+  res 0x1000
+big: data 0
+";
+  let program = assemble(source);
+  assert!(symbol(&program, "big") > 0x0FFF);
+  let machine = run(&program);
+  assert_eq!(machine.memory[0x80], 10);
+  assert_eq!(machine.memory[symbol(&program, "big") as usize], 10);
+
+  let options = Options { no_legalize: true, ..Options::default() };
+  let (result, _) = catch_errors(|| {
+    Program::from_source(Path::new("test.asm"), source, &options)
+  });
+  match result {
+    Ok(_) => panic!("register memory above 0x0FFF assembled"),
+    Err(error) =>
+      assert!(error.message.starts_with("Register memory is out of range")),
+  }
+}
+
+#[test]
+fn structured_blocks() {
+  // the sum of the odd numbers below 10, stopping once it's over 12; and
+  // whether it stopped early
+  let program = assemble("\
+equ i 0x80
+equ sum 0x81
+equ early 0x82
+equ odd 0x83
+main:
+  mi i, 0
+  mi sum, 0
+  mi early, 0
+  while i < #10
+    mv odd, i
+    ndi odd, 1
+    inc i
+    if odd == #0
+      continue
+    endif
+    ad sum, i
+    sbi sum, 1
+    if sum > #12
+      mi early, 1
+      break
+    else
+      mi 0x84, 1
+    endif
+  endwhile
+  hf
+");
+  let machine = run(&program);
+  // 1 + 3 + 5 is 9, and adding 7 makes 16
+  assert_eq!(&machine.memory[0x80..0x82], [8, 16]);
+  assert_eq!(machine.memory[0x82], 1);
+  assert_eq!(machine.memory[0x84], 1);
+}
+
+#[test]
+fn crt0_calls_the_entry_then_exits() {
+  let options = Options {
+    crt0: true,
+    stack_base: 0x380,
+    exit: Some("done".to_owned()),
+    ..Options::default()
+  };
+  let program = assemble_with("\
+entry start
+start:
+  mv 0x80, sp
+  mv 0x81, bp
+  ret
+done:
+  mv 0x82, sp
+  mi 0x83, 1
+  hf
+", &options);
+  let machine = run(&program);
+  assert_eq!(&machine.memory[0x80..0x84], [0x381, 0x380, 0x380, 1]);
+}
+
+#[test]
+fn branch_pseudo_ops() {
+  // (the branch, a, b, whether it's taken); jne, jge, jle and their
+  // immediate forms skip their `ji' with $ + 5 when they aren't taken
+  let cases: &[(&str, u16, u16, bool)] = &[
+    ("jne a, b", 3, 3, false),
+    ("jne a, b", 3, 4, true),
+    ("jge a, b", 3, 3, true),
+    ("jge a, b", 4, 3, true),
+    ("jge a, b", 2, 3, false),
+    ("jle a, b", 3, 3, true),
+    ("jle a, b", 2, 3, true),
+    ("jle a, b", 4, 3, false),
+    ("jz a", 0, 0, true),
+    ("jz a", 5, 0, false),
+    ("jnz a", 0, 0, false),
+    ("jnz a", 5, 0, true),
+    ("jgs a, b", 1, 0xFFFF, true),
+    ("jgs a, b", 0xFFFF, 1, false),
+    ("jgs a, b", 0x8000, 0x7FFF, false),
+    ("jls a, b", 0xFFFF, 1, true),
+    ("jls a, b", 1, 0xFFFF, false),
+    ("jqi a, 5", 5, 0, true),
+    ("jqi a, 5", 6, 0, false),
+    ("jgi a, 5", 6, 0, true),
+    ("jgi a, 5", 5, 0, false),
+    ("jli a, 5", 4, 0, true),
+    ("jli a, 5", 5, 0, false),
+    ("jnei a, 5", 5, 0, false),
+    ("jnei a, 5", 6, 0, true),
+    ("jgei a, 5", 5, 0, true),
+    ("jgei a, 5", 4, 0, false),
+    ("jlei a, 5", 5, 0, true),
+    ("jlei a, 5", 6, 0, false),
+  ];
+  for &(branch, a, b, taken) in cases {
+    let program = assemble(&format!("\
+equ a 0x80
+equ b 0x81
+main:
+  mi a, {}
+  mi b, {}
+  {}, yes
+  mi 0x82, 1
+  hf
+yes:
+  mi 0x82, 2
+  hf
+", a, b, branch));
+    let expected = if taken { 2 } else { 1 };
+    assert_eq!(run(&program).memory[0x82], expected, "{} with {}, {}",
+               branch, a, b);
+  }
+
+  assert_eq!(assemble_error("\
+main:
+  jgs 0x80, sc1, main
+"), "jgs can't compare sc0 or sc1");
+  assert_eq!(assemble_error("\
+main:
+  jls sc0, 0x80, main
+"), "jls can't compare sc0 or sc1");
+}
+
+#[test]
+fn multiply_and_divide() {
+  // (the op, x, y, x afterwards); y is in memory, so these call the
+  // runtime routines
+  let cases: &[(&str, u16, u16, u16)] = &[
+    ("mul", 123, 45, 5535),
+    ("mul", 0xFFFD, 300, 0xFC7C),
+    ("divu", 100, 7, 14),
+    ("modu", 100, 7, 2),
+    ("divu", 0xFFFE, 2, 0x7FFF),
+    ("divu", 100, 0, 0xFFFF),
+    ("modu", 100, 0, 100),
+    ("divs", 0xFF9C, 7, 0xFFF2),
+    ("mods", 0xFF9C, 7, 0xFFFE),
+    ("divs", 100, 0xFFF9, 0xFFF2),
+    ("mods", 100, 0xFFF9, 2),
+    ("divs", 0xFF9C, 0xFFF9, 14),
+    ("mods", 0xFF9C, 0xFFF9, 0xFFFE),
+  ];
+  for &(op, x, y, expected) in cases {
+    let program = assemble(&format!("\
+main:
+  mi 0x80, {}
+  mi 0x81, {}
+  {} 0x80, 0x81
+  hf
+", x, y, op));
+    assert_eq!(run(&program).memory[0x80], expected, "{} {}, {}", op, x, y);
+  }
+
+  // by a constant, inline, even when it's an equ
+  let program = assemble("\
+equ K 6
+equ NEG 0xFFFD
+main:
+  mi 0x80, 7
+  mul 0x80, #K
+  mi 0x81, 300
+  mul 0x81, #NEG
+  mi 0x82, 100
+  divu 0x82, #8
+  mi 0x83, 100
+  modu 0x83, #8
+  mi 0x84, 9
+  mul 0x84, #0
+  hf
+");
+  assert_eq!(program.parser.label("__mul"), None);
+  assert_eq!(program.parser.label("__divu"), None);
+  assert_eq!(&run(&program).memory[0x80..0x85], [42, 0xFC7C, 12, 4, 0]);
+
+  assert_eq!(assemble_error("\
+main:
+  mul sc1, #3
+"), "sc0 and sc1 can't be the destination of mul");
+  assert_eq!(assemble_error("\
+main:
+  divu sc0, #4
+"), "sc0 and sc1 can't be the destination of divu");
+}
+
+#[test]
+fn immediate_ops() {
+  let program = assemble("\
+equ MASK 0x0FF0
+main:
+  mi 0x80, 40
+  adi 0x80, 2
+  mi 0x81, 50
+  sbi 0x81, 8
+  mi 0x82, 0x1234
+  ndi 0x82, MASK
+  mi 0x83, 0x1200
+  ori 0x83, 0x34
+  mi 0x84, 0xFF00
+  xri 0x84, 0xFFFF
+  mi 0x85, 0x80
+  sri 0x85, 4
+  mi 0x86, 3
+  sli 0x86, MASK >> 10
+  mi 0x87, 0x8000
+  sai 0x87, 3
+  hf
+");
+  assert_eq!(
+    &run(&program).memory[0x80..0x88],
+    [42, 42, 0x0230, 0x1234, 0x00FF, 8, 0x18, 0xF000],
+  );
+
+  // the identities are removed, even by an equ, with a note
+  let source = "\
+equ NONE 0
+main:
+  mi 0x80, 7
+  adi 0x80, NONE
+  sli 0x80, 0
+  ndi 0x80, 0xFFFF
+  hf
+";
+  let (program, reported) = catch_errors(|| {
+    Program::from_source(Path::new("test.asm"), source, &Options::default())
+  });
+  let program = match program {
+    Ok(program) => program,
+    Err(error) => panic!("{}", error.message),
+  };
+  let notes = reported.iter()
+    .map(|note| (note.level, &*note.message, note.position.clone()))
+    .collect::<Vec<_>>();
+  let at = |line| Some(("test.asm".to_owned(), line));
+  assert_eq!(notes, [
+    (Level::Note, "`adi' by 0x0 has no effect, and was removed", at(4)),
+    (Level::Note, "`sli' by 0x0 has no effect, and was removed", at(5)),
+    (Level::Note, "`ndi' by 0xFFFF has no effect, and was removed", at(6)),
+  ]);
+  // mi 0x80, 7 and hf
+  assert_eq!(program.parser.lines().len(), 2);
+  assert_eq!(run(&program).memory[0x80], 7);
+}
+
+#[test]
+fn reserve_sizes_are_expressions() {
+  let program = assemble("\
+equ N 3
+struct Point
+  x: 1
+  y: 1
+endstruct
+main:
+  hf
+buf: res N * Point.size
+end: data 2
+");
+  assert_eq!(symbol(&program, "end") - symbol(&program, "buf"), 6);
+  assert_eq!(words(&program, "buf", 7), [0, 0, 0, 0, 0, 0, 2]);
+
+  assert_eq!(assemble_error("\
+main:
+  hf
+buf: res main
+"), "Expected a constant which doesn't depend on any labels");
+  assert_eq!(assemble_error("\
+main:
+  hf
+buf: res 0xF000
+"), "The program doesn't fit below 0x10000");
+  assert_eq!(assemble_error("\
+main:
+  hf
+  res 0xEFFF
+  data 1, 2
+"), "The program doesn't fit below 0x10000");
+}
+
+#[test]
+fn checksums() {
+  // the routine is football/cksum.asm's; the crc is binascii.crc_hqx's
+  let program = assemble("\
+equ s00 0x40
+equ s01 0x41
+equ s02 0x42
+equ s03 0x43
+equ s04 0x44
+main:
+  mi sp, 0x300
+  mi s00, table
+  mi s01, table_end - table
+  call cksum
+  mv 0x80, s00
+  hf
+cksum:
+  mi s02, 0x0
+  mi s03, 0x0
+  ad s01, s00
+loop:
+  jq s00, s01, done
+  md s04, s00
+  ad s02, s04
+  ad s03, s02
+  mi sc0, 0xFF00
+  nd s04, sc0
+  mi sc0, 8
+  sr s04, sc0
+  ad s03, s04
+  inc s00
+  ji loop
+done:
+  mi sc0, 0xFF
+  nd s02, sc0
+  nd s03, sc0
+  mi sc0, 8
+  sl s02, sc0
+  mv s00, s03
+  or s00, s02
+  ret
+checksum CKSUM, table, table_end, cksum
+checksum SUM, table, table_end, sum
+checksum XOR, table, table_end, xor
+checksum CRC, table, table_end, crc16
+table: data 0x1234, 0xFF00, 0x00FF, 0xABCD, 0x0001
+table_end:
+");
+  assert_eq!(words(&program, "CKSUM", 1), [0x0158]);
+  assert_eq!(words(&program, "SUM", 1), [0xBE01]);
+  assert_eq!(words(&program, "XOR", 1), [0x4607]);
+  assert_eq!(words(&program, "CRC", 1), [0x8DC6]);
+  assert_eq!(run(&program).memory[0x80], 0x0158);
+}
+
+#[test]
+fn symbol_table_tells_labels_from_equs() {
+  let program = assemble("\
+public equ COUNT 0x1002
+public main
+main:
+  hf
+buf: data 1
+");
+  let mut symbols = program.parser.symbol_table();
+  symbols.sort_by_key(|&(name, _, _, _)| name);
+  assert_eq!(symbols, [
+    ("COUNT", 0x1002, true, SymbolKind::Equ),
+    ("buf", 0x1003, false, SymbolKind::Label),
+    ("main", 0x1000, true, SymbolKind::Label),
+  ]);
+}
@@ -194,54 +194,7 @@ public equ s189 0xFD
 public equ s190 0xFE
 public equ s191 0xFF
 
-public equ r00 0x10
-public equ r01 0x11
-public equ r02 0x12
-public equ r03 0x13
-public equ r04 0x14
-public equ r05 0x15
-public equ r06 0x16
-public equ r07 0x17
-public equ r08 0x18
-public equ r09 0x19
-public equ r10 0x1A
-public equ r11 0x1B
-public equ r12 0x1C
-public equ r13 0x1D
-public equ r14 0x1E
-public equ r15 0x1F
-public equ r16 0x20
-public equ r17 0x21
-public equ r18 0x22
-public equ r19 0x23
-public equ r20 0x24
-public equ r21 0x25
-public equ r22 0x26
-public equ r23 0x27
-public equ r24 0x28
-public equ r25 0x29
-public equ r26 0x2A
-public equ r27 0x2B
-public equ r28 0x2C
-public equ r29 0x2D
-public equ r30 0x2E
-public equ r31 0x2F
-public equ r32 0x30
-public equ r33 0x31
-public equ r34 0x32
-public equ r35 0x33
-public equ r36 0x34
-public equ r37 0x35
-public equ r38 0x36
-public equ r39 0x37
-public equ r40 0x38
-public equ r41 0x39
-public equ r42 0x3A
-public equ r43 0x3B
-public equ r44 0x3C
-public equ r45 0x3D
-public equ r46 0x3E
-public equ r47 0x3F
+; deprecated: r48-r51 were never callee-save, they're s00-s03
 public equ r48 0x40
 public equ r49 0x41
 public equ r50 0x42